@@ -0,0 +1,8 @@
+//! Kernel modules without hardware access, built for the host so their tests can run there.
+
+#[path = "../../src/bootargs.rs"]
+pub mod bootargs;
+#[path = "../../src/elf.rs"]
+pub mod elf;
+#[path = "../../src/fdt.rs"]
+pub mod fdt;
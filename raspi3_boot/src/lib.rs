@@ -47,11 +47,21 @@ macro_rules! entry {
     };
 }
 
-/// Reset function.
+/// The kernel is linked and runs from here, in the TTBR1 high half which maps physical memory
+/// linearly: physical `pa` is at `KERNEL_VA_BASE + pa`. Same as in link.ld and boot_cores.S.
+pub const KERNEL_VA_BASE: u64 = 0xFFFF_FF80_0000_0000;
+
+/// Exception level the firmware started core 0 at. Set by `reset`.
+static mut BOOT_EL: u32 = 0;
+/// Physical address of the device tree blob the firmware gave in x0. Set by `reset`.
+static mut DTB: u64 = 0;
+
+/// Reset function. Entered at EL1 from `_boot_cores` with the MMU on, with the device tree
+/// address from the firmware and the EL we were started at.
 ///
 /// Initializes the bss section before calling into the user's `main()`.
 #[no_mangle]
-pub unsafe extern "C" fn reset() -> ! {
+pub unsafe extern "C" fn reset(dtb: u64, boot_el: u64) -> ! {
     extern "C" {
         // Boundaries of the .bss section, provided by the linker script
         static mut __bss_start: u64;
@@ -60,6 +70,8 @@ pub unsafe extern "C" fn reset() -> ! {
 
     // Zeroes the .bss section
     r0::zero_bss(&mut __bss_start, &mut __bss_end);
+    BOOT_EL = boot_el as u32;
+    DTB = dtb;
 
     extern "Rust" {
         fn main() -> !;
@@ -68,11 +80,89 @@ pub unsafe extern "C" fn reset() -> ! {
     main();
 }
 
-// Disable all cores except core 0, and then jump to reset()
+/// Exception level (1-3) the firmware started us at. The kernel always runs at EL1.
+pub fn boot_el() -> u32 {
+    unsafe { BOOT_EL }
+}
+
+/// Physical address of the device tree blob, if the firmware passed one. It is not checked.
+pub fn dtb_address() -> Option<u64> {
+    match unsafe { DTB } {
+        0 => None,
+        a => Some(a),
+    }
+}
+
+// Park all cores except core 0, and enter reset() at EL1 from EL3, EL2 or EL1 with the MMU on.
+// The linker script must define `__el2_stack_top`, `__el1_stack0_top`, `__dma_pool_start` and
+// `__heap_end`.
 global_asm!(include_str!("boot_cores.S"));
 
 extern "C" {
     fn _enable_irq();
+    fn _secondary_start();
+    fn __replace_ttbr1();
+}
+
+/// Number of Cortex-A53 cores.
+pub const CORES: usize = 4;
+
+/// Release address of core n is at SPIN_TABLE_BASE + 8 * n.
+const SPIN_TABLE_BASE: usize = 0xD8;
+
+#[repr(C)]
+struct SecondaryBoot {
+    stack: u64,
+    entry: u64,
+}
+
+/// Read by `_secondary_start` in boot_cores.S.
+#[allow(non_upper_case_globals)]
+#[no_mangle]
+static mut __secondary_boot: [SecondaryBoot; CORES] = [
+    SecondaryBoot { stack: 0, entry: 0 },
+    SecondaryBoot { stack: 0, entry: 0 },
+    SecondaryBoot { stack: 0, entry: 0 },
+    SecondaryBoot { stack: 0, entry: 0 },
+];
+
+/// Start a secondary core (1-3) at `entry` in EL1, with `stack` as its stack top.
+/// The core may be parked at EL3 or EL2, it enters EL1 the same way core 0 does.
+/// `entry` receives the core number.
+///
+/// Returns false for an invalid core number.
+pub unsafe fn start_core(core: usize, entry: extern "C" fn(usize) -> !, stack: u64) -> bool {
+    if core == 0 || core >= CORES {
+        return false;
+    }
+
+    __secondary_boot[core] = SecondaryBoot {
+        stack: stack & !0xF,
+        entry: entry as u64,
+    };
+    let boot = &__secondary_boot[core] as *const SecondaryBoot;
+    asm!("dc civac, $0" :: "r"(boot) : "memory" : "volatile");
+
+    // the core starts with its MMU off.
+    let release = (KERNEL_VA_BASE + (SPIN_TABLE_BASE + core * 8) as u64) as *mut u64;
+    core::ptr::write_volatile(release, _secondary_start as u64 - KERNEL_VA_BASE);
+    asm!("dc civac, $0" :: "r"(release) : "memory" : "volatile");
+
+    // make the writes visible, then wake the core up from wfe.
+    asm!("dsb sy" :::: "volatile");
+    asm!("sev" :::: "volatile");
+    true
+}
+
+/// Install `ttbr1` and drop the TLB entries of the previous one. Runs from the identity map of
+/// the boot tables, so they must still be in TTBR0.
+///
+/// # Safety
+///
+/// - The new tables must map the kernel where the boot tables do. Call with interrupts masked.
+pub unsafe fn replace_ttbr1(ttbr1: u64) {
+    let physical = __replace_ttbr1 as u64 - KERNEL_VA_BASE;
+    asm!("blr $1" :: "{x0}"(ttbr1), "r"(physical) : "x30", "memory" : "volatile");
 }
 
 /// Enable irq at CPU.
@@ -85,6 +175,36 @@ pub unsafe fn disable_irq() {
     asm!("msr daifset, #2");
 }
 
+/// Whether irq is masked at CPU.
+pub fn irq_masked() -> bool {
+    let daif: u64;
+    unsafe { asm!("mrs $0, daif" : "=r"(daif) : : : "volatile") };
+    daif & (1 << 7) != 0
+}
+
+/// Mask irq and fiq, and return the previous DAIF to give to `restore_interrupts`.
+pub unsafe fn save_and_disable_interrupts() -> u64 {
+    let daif: u64;
+    asm!("mrs $0, daif" : "=r"(daif) : : "memory" : "volatile");
+    asm!("msr daifset, #3" : : : "memory" : "volatile");
+    daif
+}
+
+/// Restore the irq and fiq masks saved by `save_and_disable_interrupts`.
+pub unsafe fn restore_interrupts(daif: u64) {
+    asm!("msr daif, $0" :: "r"(daif) : "memory" : "volatile");
+}
+
+/// Enable fiq at CPU.
+pub unsafe fn enable_fiq() {
+    asm!("msr daifclr, #1");
+}
+
+/// Disable fiq
+pub unsafe fn disable_fiq() {
+    asm!("msr daifset, #1");
+}
+
 /// Sleep CPU
 pub unsafe fn wfe() {
     asm!("wfe");
@@ -1,15 +1,58 @@
-use crate::optional_cell::OptionalCell;
+use crate::event::EventCounter;
+use crate::interrupt::{BasicInterruptId, IrqSource};
+use crate::mbox;
+use core::cell::Cell;
+use core::sync::atomic::{compiler_fence, Ordering};
 use register::{
     mmio::{ReadOnly, ReadWrite, WriteOnly},
     register_bitfields,
 };
 
-const TIMER_BASE: u32 = super::MMIO_BASE + 0xB400;
+const TIMER_BASE: u64 = super::MMIO_BASE + 0xB400;
 
 pub struct ArmTimer {
-    occurred: OptionalCell<bool>,
+    events: EventCounter,
+    one_shot: Cell<bool>,
+    free_run_frequency: Cell<u32>,
 }
 
+pub enum ArmTimerError {
+    MailboxError,
+    InvalidFrequency,
+}
+pub type Result<T> = ::core::result::Result<T, ArmTimerError>;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum TimerMode {
+    /// Counter is reloaded from RELOAD and raises interrupts repeatedly.
+    Periodic,
+    /// Timer is stopped at the first interrupt.
+    OneShot,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum CounterWidth {
+    Bit16,
+    Bit32,
+}
+
+/// Settings applied by `ArmTimer::configure`.
+pub struct ArmTimerConfig {
+    /// Interrupt frequency in Hz.
+    pub frequency: u32,
+    pub mode: TimerMode,
+    pub width: CounterWidth,
+    /// Frequency of the free running counter in Hz. 0 to keep it stopped.
+    pub free_run_frequency: u32,
+    /// Keep counting while the ARM is halted in debug mode.
+    pub run_in_halt: bool,
+}
+
+/// Timer clock after PRE_DIVIDER. 1MHz makes the count down value easy to read.
+const TIMER_CLOCK: u32 = 1_000_000;
+const PRE_DIVIDER_MAX: u32 = 0x3FF; // 10 bits
+const PRE_SCALAR_MAX: u32 = 0xFF; // 8 bits
+
 #[allow(non_snake_case)]
 #[repr(C)]
 pub struct RegisterBlock {
@@ -49,7 +92,7 @@ register_bitfields! {
         ],
         BIT_WIDTH OFFSET(1) NUMBITS(1)[
             BIT_16=0,
-            BIT_23=1
+            BIT_32=1
         ]
     ],
     RAW_IRQ[
@@ -74,13 +117,14 @@ impl core::ops::Deref for ArmTimer {
     }
 }
 
-const BASIC_INT_NO_ARM_TIMER: u32 = 0;
-
 impl crate::exception::InterruptionSource for ArmTimer {
-    fn on_interruption(&self, id: u32) {
-        if id == BASIC_INT_NO_ARM_TIMER {
+    fn on_interruption(&self, source: IrqSource) {
+        if source == IrqSource::Basic(BasicInterruptId::ArmTimer) {
             self.clear_irq();
-            self.occurred.insert(Some(true));
+            if self.one_shot.get() {
+                self.disable();
+            }
+            self.events.signal();
         }
     }
 }
@@ -89,20 +133,114 @@ impl crate::exception::InterruptionSource for ArmTimer {
 impl ArmTimer {
     pub fn new() -> ArmTimer {
         ArmTimer {
-            occurred: OptionalCell::new(false),
+            events: EventCounter::new(),
+            one_shot: Cell::new(false),
+            free_run_frequency: Cell::new(0),
         }
     }
     fn ptr() -> *const RegisterBlock {
         TIMER_BASE as *const _
     }
 
+    /// Read the APB clock which feeds both the timer and the free running counter.
+    pub fn get_apb_clock(&self, mbox: &mut mbox::Mbox) -> Result<u32> {
+        mbox.buffer[0] = 8 * 4;
+        mbox.buffer[1] = mbox::REQUEST;
+        mbox.buffer[2] = mbox::tag::GETCLKRATE;
+        mbox.buffer[3] = 8;
+        mbox.buffer[4] = 0;
+        mbox.buffer[5] = mbox::clock::CORE;
+        mbox.buffer[6] = 0; // rate is returned here.
+        mbox.buffer[7] = mbox::tag::LAST;
+
+        compiler_fence(Ordering::Release);
+
+        if mbox.call(mbox::channel::PROP).is_err() {
+            return Err(ArmTimerError::MailboxError);
+        }
+
+        match mbox.buffer[6] {
+            0 => Err(ArmTimerError::MailboxError),
+            rate => Ok(rate),
+        }
+    }
+
+    /// Stop the timer and apply all settings. Call `enable` to start it.
+    pub fn configure(&self, config: &ArmTimerConfig, mbox: &mut mbox::Mbox) -> Result<()> {
+        let apb = self.get_apb_clock(mbox)?;
+
+        if config.frequency == 0 || config.frequency > TIMER_CLOCK || apb < TIMER_CLOCK {
+            return Err(ArmTimerError::InvalidFrequency);
+        }
+        let pre_divider = apb / TIMER_CLOCK - 1;
+        if pre_divider > PRE_DIVIDER_MAX {
+            return Err(ArmTimerError::InvalidFrequency);
+        }
+
+        let max = match config.width {
+            CounterWidth::Bit16 => 0xFFFF,
+            CounterWidth::Bit32 => 0xFFFF_FFFF,
+        };
+        // pick the finest pre-scale which can hold the count.
+        let (pre_scale, load) = match TIMER_CLOCK / config.frequency {
+            t if t <= max => (CONTROL::PRE_SCALE::C_1_1, t),
+            t if t / 16 <= max => (CONTROL::PRE_SCALE::C_1_16, t / 16),
+            t if t / 256 <= max => (CONTROL::PRE_SCALE::C_1_256, t / 256),
+            _ => return Err(ArmTimerError::InvalidFrequency),
+        };
+        if load == 0 {
+            return Err(ArmTimerError::InvalidFrequency);
+        }
+
+        let width = match config.width {
+            CounterWidth::Bit16 => CONTROL::BIT_WIDTH::BIT_16,
+            CounterWidth::Bit32 => CONTROL::BIT_WIDTH::BIT_32,
+        };
+        let free_run = if config.free_run_frequency == 0 {
+            self.free_run_frequency.set(0);
+            CONTROL::FREE_RUN::Disabled + CONTROL::PRE_SCALAR.val(0)
+        } else {
+            let pre_scalar = (apb / config.free_run_frequency).saturating_sub(1);
+            if pre_scalar > PRE_SCALAR_MAX {
+                return Err(ArmTimerError::InvalidFrequency);
+            }
+            self.free_run_frequency.set(apb / (pre_scalar + 1));
+            CONTROL::FREE_RUN::Enabled + CONTROL::PRE_SCALAR.val(pre_scalar)
+        };
+
+        // stop everything before touching dividers.
+        self.CONTROL
+            .write(CONTROL::ENABLED::Disabled + CONTROL::INT_EN::Disabled);
+        self.PRE_DIVIDER.set(pre_divider);
+        self.CONTROL.write(
+            width
+                + pre_scale
+                + free_run
+                + CONTROL::RUN_IN_HALT.val(config.run_in_halt as u32),
+        );
+        self.LOAD.set(load);
+        self.RELOAD.set(load);
+        self.clear_irq();
+        self.one_shot.set(config.mode == TimerMode::OneShot);
+
+        Ok(())
+    }
+
     pub fn enable(&self) {
         self.CONTROL.modify(CONTROL::ENABLED::Enabled);
     }
 
+    pub fn disable(&self) {
+        self.CONTROL.modify(CONTROL::ENABLED::Disabled);
+    }
+
     pub fn start_free_run(&self) {
-        self.CONTROL
-            .modify(CONTROL::FREE_RUN::Enabled + CONTROL::BIT_WIDTH::BIT_23);
+        self.CONTROL.modify(CONTROL::FREE_RUN::Enabled);
+    }
+
+    /// Frequency of the free running counter set by `configure`. 0 if unknown.
+    pub fn get_free_run_frequency(&self) -> u32 {
+        self.free_run_frequency.get()
     }
 
     pub fn read_free_run(&self) -> u32 {
@@ -113,6 +251,11 @@ impl ArmTimer {
         self.LOAD.set(t);
     }
 
+    /// Value loaded at the next wrap, without restarting the current count.
+    pub fn set_reload(&self, t: u32) {
+        self.RELOAD.set(t);
+    }
+
     pub fn read_count_down(&self) -> u32 {
         self.VALUE.get()
     }
@@ -126,9 +269,10 @@ impl ArmTimer {
     }
 
     pub fn occurred(&self) -> bool {
-        match self.occurred.take() {
-            Some(f) => f,
-            None => false,
-        }
+        self.events.take() != 0
+    }
+
+    pub fn events(&self) -> &EventCounter {
+        &self.events
     }
 }
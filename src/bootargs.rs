@@ -0,0 +1,122 @@
+//! Syntax of the kernel command line: `key=value` options separated by spaces. Values may be
+//! quoted to hold spaces. A key alone has the value "".
+
+#[derive(Debug, PartialEq)]
+pub enum SizeError {
+    BadValue,
+    /// Does not fit in 64 bits.
+    OutOfRange,
+}
+pub type Result<T> = ::core::result::Result<T, SizeError>;
+
+/// `(key, value)` pairs of `line`.
+pub fn options(line: &str) -> Options<'_> {
+    Options { rest: line }
+}
+
+pub struct Options<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Iterator for Options<'a> {
+    type Item = (&'a str, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let s = self.rest.trim_start();
+        if s.is_empty() {
+            return None;
+        }
+
+        // spaces between quotes belong to the option.
+        let mut quoted = false;
+        let end = s
+            .char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    quoted = !quoted;
+                }
+                c == ' ' && !quoted
+            })
+            .map_or(s.len(), |(i, _)| i);
+        self.rest = &s[end..];
+
+        let option = &s[..end];
+        Some(match option.find('=') {
+            Some(i) => (&option[..i], option[i + 1..].trim_matches('"')),
+            None => (option, ""),
+        })
+    }
+}
+
+/// Decimal or 0x prefixed hex number, with an optional K, M or G suffix.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let (digits, shift) = match s.as_bytes().last() {
+        Some(b'k') | Some(b'K') => (&s[..s.len() - 1], 10),
+        Some(b'm') | Some(b'M') => (&s[..s.len() - 1], 20),
+        Some(b'g') | Some(b'G') => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    let v = if digits.starts_with("0x") || digits.starts_with("0X") {
+        u64::from_str_radix(&digits[2..], 16)
+    } else {
+        digits.parse::<u64>()
+    }
+    .map_err(|_| SizeError::BadValue)?;
+    v.checked_mul(1 << shift).ok_or(SizeError::OutOfRange)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_options() {
+        assert_eq!(
+            options("heap=16M  quiet dma.burst=4 ").collect::<Vec<_>>(),
+            [("heap", "16M"), ("quiet", ""), ("dma.burst", "4")]
+        );
+        assert_eq!(options("").count(), 0);
+        assert_eq!(options("   ").count(), 0);
+    }
+
+    #[test]
+    fn quotes_hold_spaces() {
+        assert_eq!(
+            options(r#"name="two words" x=1"#).collect::<Vec<_>>(),
+            [("name", "two words"), ("x", "1")]
+        );
+        // an unterminated quote runs to the end.
+        assert_eq!(
+            options(r#"name="a b"#).collect::<Vec<_>>(),
+            [("name", "a b")]
+        );
+    }
+
+    #[test]
+    fn value_keeps_later_equal_signs() {
+        assert_eq!(options("a=b=c").collect::<Vec<_>>(), [("a", "b=c")]);
+        assert_eq!(options("a=").collect::<Vec<_>>(), [("a", "")]);
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("0"), Ok(0));
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("0x1000"), Ok(0x1000));
+        assert_eq!(parse_size("0XfF"), Ok(0xFF));
+        assert_eq!(parse_size("4k"), Ok(4 << 10));
+        assert_eq!(parse_size("16M"), Ok(16 << 20));
+        assert_eq!(parse_size("0x2G"), Ok(2 << 30));
+    }
+
+    #[test]
+    fn rejects_bad_sizes() {
+        assert_eq!(parse_size(""), Err(SizeError::BadValue));
+        assert_eq!(parse_size("M"), Err(SizeError::BadValue));
+        assert_eq!(parse_size("0x"), Err(SizeError::BadValue));
+        assert_eq!(parse_size("12T"), Err(SizeError::BadValue));
+        assert_eq!(parse_size("-1"), Err(SizeError::BadValue));
+        assert_eq!(parse_size("18446744073709551616"), Err(SizeError::BadValue));
+        assert_eq!(parse_size("0x400000000G"), Err(SizeError::OutOfRange));
+    }
+}
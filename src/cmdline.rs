@@ -0,0 +1,133 @@
+//! Kernel command line from /chosen/bootargs or the mailbox. `bootargs` splits it.
+//!
+//! Modules declare the options they take with `register`, then `apply` hands each value to its
+//! option and warns about the rest.
+
+use crate::bootargs::{self, SizeError};
+use crate::mbox;
+use crate::uart::Uart;
+use core::sync::atomic::{compiler_fence, Ordering};
+
+/// Options which can be registered.
+const MAX_PARAMS: usize = 16;
+/// Longest command line read from the mailbox.
+const MAX_LEN: usize = 1024;
+
+pub enum CmdlineError {
+    /// All slots are in use.
+    NoSpace,
+    AlreadyRegistered,
+    BadValue,
+    OutOfRange,
+}
+pub type Result<T> = ::core::result::Result<T, CmdlineError>;
+
+impl From<SizeError> for CmdlineError {
+    fn from(e: SizeError) -> CmdlineError {
+        match e {
+            SizeError::BadValue => CmdlineError::BadValue,
+            SizeError::OutOfRange => CmdlineError::OutOfRange,
+        }
+    }
+}
+
+/// An option and what to do with its value.
+pub struct Param {
+    pub name: &'static str,
+    pub set: fn(&'static str) -> Result<()>,
+}
+
+static mut PARAMS: [Option<&'static Param>; MAX_PARAMS] = [None; MAX_PARAMS];
+static mut LINE: &str = "";
+/// The mailbox returns the command line here.
+static mut MBOX_LINE: [u8; MAX_LEN] = [0; MAX_LEN];
+
+/// Add an option for `apply`.
+///
+/// # Safety
+///
+/// - Must not race with `register` or `apply`: call while booting on core 0.
+pub unsafe fn register(param: &'static Param) -> Result<()> {
+    if PARAMS.iter().flatten().any(|p| p.name == param.name) {
+        return Err(CmdlineError::AlreadyRegistered);
+    }
+    match PARAMS.iter_mut().find(|p| p.is_none()) {
+        Some(slot) => {
+            *slot = Some(param);
+            Ok(())
+        }
+        None => Err(CmdlineError::NoSpace),
+    }
+}
+
+/// Take the command line from the device tree, or ask the firmware when there is none.
+///
+/// # Safety
+///
+/// - Same as `register`.
+pub unsafe fn init(bootargs: Option<&'static str>, mbox: &mut mbox::Mbox) {
+    LINE = match bootargs {
+        Some(line) => line,
+        None => from_mailbox(mbox).unwrap_or(""),
+    };
+}
+
+unsafe fn from_mailbox(mbox: &mut mbox::Mbox) -> Option<&'static str> {
+    let words = MAX_LEN / 4;
+    mbox.buffer[0] = ((6 + words) * 4) as u32;
+    mbox.buffer[1] = mbox::REQUEST;
+    mbox.buffer[2] = mbox::tag::GETCMDLINE;
+    mbox.buffer[3] = MAX_LEN as u32;
+    mbox.buffer[4] = 0;
+    for w in &mut mbox.buffer[5..5 + words] {
+        *w = 0;
+    }
+    mbox.buffer[5 + words] = mbox::tag::LAST;
+    compiler_fence(Ordering::Release);
+
+    mbox.call(mbox::channel::PROP).ok()?;
+
+    // bit 31 marks the response, the rest is the length. It is cut at MAX_LEN.
+    let len = core::cmp::min((mbox.buffer[4] & 0x7FFF_FFFF) as usize, MAX_LEN);
+    for (i, b) in MBOX_LINE[..len].iter_mut().enumerate() {
+        *b = (mbox.buffer[5 + i / 4] >> (8 * (i % 4))) as u8;
+    }
+    let line = &MBOX_LINE[..len];
+    let end = line.iter().position(|&b| b == 0).unwrap_or(len);
+    core::str::from_utf8(&line[..end]).ok()
+}
+
+/// The command line given to `init`.
+pub fn line() -> &'static str {
+    unsafe { LINE }
+}
+
+/// Hand each option of the command line to its registered `Param`. Unknown keys and bad values
+/// are reported and skipped.
+///
+/// # Safety
+///
+/// - Same as `register`.
+pub unsafe fn apply(uart: &Uart) {
+    for (key, value) in bootargs::options(LINE) {
+        match PARAMS.iter().flatten().find(|p| p.name == key) {
+            Some(p) => {
+                if let Err(e) = (p.set)(value) {
+                    uart.puts("Ignoring ");
+                    uart.puts(key);
+                    uart.puts("=");
+                    uart.puts(value);
+                    uart.puts(match e {
+                        CmdlineError::OutOfRange => ": out of range\n",
+                        _ => ": bad value\n",
+                    });
+                }
+            }
+            None => {
+                uart.puts("Unknown kernel parameter ");
+                uart.puts(key);
+                uart.puts("\n");
+            }
+        }
+    }
+}
@@ -1,4 +1,6 @@
-use crate::optional_cell::OptionalCell;
+use crate::event::EventFlags;
+use crate::thread_sync::Queue;
+use core::cell::Cell;
 use core::sync::atomic::compiler_fence;
 use register::{mmio::ReadWrite, register_bitfields, InMemoryRegister};
 
@@ -323,7 +325,7 @@ impl ControlBlock {
     }
 }
 
-const DMAC_BASE: u32 = super::MMIO_BASE + 0x7000;
+const DMAC_BASE: u64 = super::MMIO_BASE + 0x7000;
 
 #[allow(dead_code)]
 impl core::ops::Deref for DMAC {
@@ -357,13 +359,17 @@ impl DMAC {
 
     pub fn exec(&self, cs: &ControlBlock) {
         let raw_addr: *const ControlBlock = cs;
-        self.Channels[0].CONBLK_AD.set(raw_addr as u32);
+        self.Channels[0]
+            .CONBLK_AD
+            .set(crate::mmu::virt_to_phys(raw_addr as u64) as u32);
         self.Channels[0].CS.write(CS::ACTIVE::Enable);
     }
 
     pub fn exec4(&self, cs: &ControlBlock4) {
         let raw_addr: *const ControlBlock4 = cs;
-        self.Channels[0].CONBLK_AD.set(raw_addr as u32);
+        self.Channels[0]
+            .CONBLK_AD
+            .set(crate::mmu::virt_to_phys(raw_addr as u64) as u32);
         self.Channels[0].CS.write(CS::ACTIVE::Enable);
     }
 
@@ -385,9 +391,9 @@ pub struct DMAC0 {}
 impl DMAC0 {
     pub fn write_data() {
         unsafe {
-            (*(0x3F00_7200 as *mut u32)) = 1; // CS
-            (*(0x3F00_7204 as *mut u32)) = 1; // CONBLK_AD
-                                              // ...
+            (*((super::MMIO_BASE + 0x7200) as *mut u32)) = 1; // CS
+            (*((super::MMIO_BASE + 0x7204) as *mut u32)) = 1; // CONBLK_AD
+                                                              // ...
         }
     }
 }
@@ -404,7 +410,7 @@ pub struct DmacRegs {
 #[allow(dead_code)]
 impl DMAC1 {
     pub fn write_data() {
-        const BASE: u32 = 0x3F00_7200;
+        const BASE: u64 = super::MMIO_BASE + 0x7200;
         let mut register: *mut DmacRegs = BASE as *mut DmacRegs;
         unsafe {
             (*register).CS = 1;
@@ -415,7 +421,7 @@ impl DMAC1 {
 }
 
 pub struct DMAC2 {
-    base_addr: u32,
+    base_addr: u64,
 }
 
 #[allow(non_snake_case)]
@@ -446,7 +452,7 @@ impl DMAC2 {
     }
 
     pub fn new() -> DMAC2 {
-        let b: u32 = 0x3F00_7200;
+        let b: u64 = super::MMIO_BASE + 0x7200;
         DMAC2 { base_addr: b }
     }
 
@@ -501,7 +507,7 @@ impl DMAC3 {
     }
 
     fn ptr() -> *const RegisterBlock3 {
-        const DMAC_BASE: u32 = 0x3F00_7200;
+        const DMAC_BASE: u64 = super::MMIO_BASE + 0x7200;
         DMAC_BASE as *const _
     }
 
@@ -585,15 +591,26 @@ impl core::ops::Deref for DMAC4 {
 }
 
 pub struct DMAC4 {
-    occurred: [OptionalCell<bool>; 16],
+    /// bit n for channel n.
+    events: EventFlags,
+    /// Receives the channel number of each completed transfer on the channels of the mask.
+    completion: Cell<Option<(&'static Queue<usize>, u16)>>,
 }
 
 impl crate::exception::InterruptionSource for DMAC4 {
-    fn on_interruption(&self, _id: u32) {
+    fn on_interruption(&self, _source: crate::interrupt::IrqSource) {
         for ch in 0..=15 {
             if self.is_interrupt_pending(ch) {
                 self.clear_interrupt(ch);
-                self.occurred[ch].insert(Some(true));
+                self.events.set(1 << ch);
+                match self.completion.get() {
+                    // nobody is draining it. the event flag is still set.
+                    Some((q, channels)) if channels & (1 << ch) != 0 => {
+                        let _ = q.try_send(ch);
+                    }
+                    // other channels belong to someone else, who only watches the flags.
+                    _ => {}
+                }
             }
         }
     }
@@ -603,7 +620,8 @@ impl crate::exception::InterruptionSource for DMAC4 {
 impl DMAC4 {
     pub fn new() -> DMAC4 {
         DMAC4 {
-            occurred: arr_macro::arr![OptionalCell::empty(); 16],
+            events: EventFlags::new(),
+            completion: Cell::new(None),
         }
     }
     fn ptr() -> *const RegisterBlock {
@@ -687,7 +705,14 @@ impl DMAC4 {
         }
         compiler_fence(core::sync::atomic::Ordering::Release);
         let raw_addr: *const ControlBlock4 = cs;
-        self.Channels[ch].CONBLK_AD.set(raw_addr as u32);
+        // the control block may be on a cached stack.
+        crate::mmu::clean_dcache_range(
+            raw_addr as u64,
+            core::mem::size_of::<ControlBlock4>() as u64,
+        );
+        self.Channels[ch]
+            .CONBLK_AD
+            .set(crate::mmu::virt_to_phys(raw_addr as u64) as u32);
         self.Channels[ch].CS.write(CS::ACTIVE::Enable);
     }
 
@@ -727,9 +752,16 @@ impl DMAC4 {
             return false;
         }
 
-        match self.occurred[ch].take() {
-            Some(f) => f,
-            None => false,
-        }
+        self.events.take(1 << ch) != 0
+    }
+
+    pub fn events(&self) -> &EventFlags {
+        &self.events
+    }
+
+    /// Post completed channel numbers to `queue` from the interrupt handler, for the channels
+    /// set in `channels` (bit n for channel n) only.
+    pub fn set_completion_queue(&self, queue: &'static Queue<usize>, channels: u16) {
+        self.completion.set(Some((queue, channels)));
     }
 }
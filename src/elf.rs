@@ -0,0 +1,432 @@
+//! ELF64 parser for programs run at EL0. `loader` puts them in an address space.
+//!
+//! Only statically linked AArch64 executables are accepted: segments go to the address they are
+//! linked at, and nothing is relocated.
+
+const EI_NIDENT: usize = 16;
+const MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_AARCH64: u16 = 183;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const SHDR_SIZE: usize = 64;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+
+const SHT_RELA: u32 = 4;
+const SHT_REL: u32 = 9;
+const SHF_ALLOC: u64 = 0x2;
+
+pub const PF_X: u32 = 0x1;
+pub const PF_W: u32 = 0x2;
+
+/// Granule of the user mappings, see `mmu::PAGE_SIZE`.
+const PAGE_SIZE: u64 = 0x1000;
+
+#[derive(Debug)]
+pub enum ElfError {
+    TooShort,
+    BadMagic,
+    NotElf64,
+    NotLittleEndian,
+    NotAarch64,
+    NotExecutable,
+    /// Dynamically linked, or has relocations left to apply.
+    NeedsRelocation,
+    BadProgramHeader,
+    /// A segment lies outside its file or the load region.
+    SegmentOutOfRange,
+    EntryOutOfRange,
+    /// A page would be both writable and executable.
+    WritableCode,
+    OutOfMemory,
+    SpawnFailed,
+}
+pub type Result<T> = ::core::result::Result<T, ElfError>;
+
+#[derive(Copy, Clone, Debug)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+}
+
+pub struct Elf<'a> {
+    data: &'a [u8],
+    entry: u64,
+    phoff: u64,
+    phnum: u16,
+    shoff: u64,
+    shnum: u16,
+}
+
+/// `off + add`, which the image is too short for when it overflows.
+fn offset(off: u64, add: u64) -> Result<u64> {
+    off.checked_add(add).ok_or(ElfError::TooShort)
+}
+
+fn u16_at(data: &[u8], off: u64) -> Result<u16> {
+    let off = off as usize;
+    match off.checked_add(2).and_then(|end| data.get(off..end)) {
+        Some(b) => Ok(u16::from(b[0]) | u16::from(b[1]) << 8),
+        None => Err(ElfError::TooShort),
+    }
+}
+
+fn u32_at(data: &[u8], off: u64) -> Result<u32> {
+    Ok(u32::from(u16_at(data, off)?) | u32::from(u16_at(data, offset(off, 2)?)?) << 16)
+}
+
+fn u64_at(data: &[u8], off: u64) -> Result<u64> {
+    Ok(u64::from(u32_at(data, off)?) | u64::from(u32_at(data, offset(off, 4)?)?) << 32)
+}
+
+impl<'a> Elf<'a> {
+    /// Check the header and return a view of the image. Nothing is copied.
+    pub fn parse(data: &'a [u8]) -> Result<Elf<'a>> {
+        if data.len() < EHDR_SIZE {
+            return Err(ElfError::TooShort);
+        }
+        if data[0..4] != MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELFCLASS64 {
+            return Err(ElfError::NotElf64);
+        }
+        if data[5] != ELFDATA2LSB {
+            return Err(ElfError::NotLittleEndian);
+        }
+
+        let e_type = u16_at(data, EI_NIDENT as u64)?;
+        if u16_at(data, 0x12)? != EM_AARCH64 {
+            return Err(ElfError::NotAarch64);
+        }
+        match e_type {
+            ET_EXEC => (),
+            ET_DYN => return Err(ElfError::NeedsRelocation),
+            _ => return Err(ElfError::NotExecutable),
+        }
+
+        let phentsize = u16_at(data, 0x36)?;
+        let phnum = u16_at(data, 0x38)?;
+        if phnum > 0 && phentsize as usize != PHDR_SIZE {
+            return Err(ElfError::BadProgramHeader);
+        }
+        let shentsize = u16_at(data, 0x3A)?;
+        let shnum = u16_at(data, 0x3C)?;
+        if shnum > 0 && shentsize as usize != SHDR_SIZE {
+            return Err(ElfError::BadProgramHeader);
+        }
+
+        let elf = Elf {
+            data,
+            entry: u64_at(data, 0x18)?,
+            phoff: u64_at(data, 0x20)?,
+            phnum,
+            shoff: u64_at(data, 0x28)?,
+            shnum,
+        };
+        // headers must be inside the image.
+        for i in 0..phnum as usize {
+            elf.program_header(i)?;
+        }
+        elf.check_relocations()?;
+        Ok(elf)
+    }
+
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    pub fn program_header(&self, i: usize) -> Result<ProgramHeader> {
+        if i >= self.phnum as usize {
+            return Err(ElfError::BadProgramHeader);
+        }
+        let off = (i as u64)
+            .checked_mul(PHDR_SIZE as u64)
+            .and_then(|o| o.checked_add(self.phoff))
+            .ok_or(ElfError::BadProgramHeader)?;
+        let field = |at: u64| off.checked_add(at).ok_or(ElfError::BadProgramHeader);
+        Ok(ProgramHeader {
+            p_type: u32_at(self.data, off)?,
+            flags: u32_at(self.data, field(0x4)?)?,
+            offset: u64_at(self.data, field(0x8)?)?,
+            vaddr: u64_at(self.data, field(0x10)?)?,
+            filesz: u64_at(self.data, field(0x20)?)?,
+            memsz: u64_at(self.data, field(0x28)?)?,
+        })
+    }
+
+    /// PT_LOAD segments and the bytes of the file they start with. Only valid after
+    /// `check_layout`.
+    pub fn segments(&self) -> impl Iterator<Item = (ProgramHeader, &'a [u8])> + '_ {
+        (0..self.phnum as usize)
+            .filter_map(move |i| self.program_header(i).ok())
+            .filter(|ph| ph.p_type == PT_LOAD)
+            .filter_map(move |ph| {
+                let start = ph.offset as usize;
+                let end = start.checked_add(ph.filesz as usize)?;
+                Some((ph, self.data.get(start..end)?))
+            })
+    }
+
+    /// Reject anything which would need relocating or a dynamic linker.
+    fn check_relocations(&self) -> Result<()> {
+        for i in 0..self.phnum as usize {
+            match self.program_header(i)?.p_type {
+                PT_DYNAMIC | PT_INTERP => return Err(ElfError::NeedsRelocation),
+                _ => (),
+            }
+        }
+        for i in 0..self.shnum as u64 {
+            let off = i
+                .checked_mul(SHDR_SIZE as u64)
+                .and_then(|o| o.checked_add(self.shoff))
+                .ok_or(ElfError::TooShort)?;
+            let sh_type = u32_at(self.data, offset(off, 0x4)?)?;
+            let sh_flags = u64_at(self.data, offset(off, 0x8)?)?;
+            let sh_size = u64_at(self.data, offset(off, 0x20)?)?;
+            if (sh_type == SHT_RELA || sh_type == SHT_REL)
+                && sh_flags & SHF_ALLOC != 0
+                && sh_size != 0
+            {
+                return Err(ElfError::NeedsRelocation);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check every PT_LOAD segment fits in its file and in [base, base + size), that the entry
+    /// point is in an executable one and that no page is both writable and executable.
+    pub fn check_layout(&self, base: u64, size: u64) -> Result<()> {
+        let end = base.checked_add(size).ok_or(ElfError::SegmentOutOfRange)?;
+        let mut entry_ok = false;
+        for i in 0..self.phnum as usize {
+            let ph = self.program_header(i)?;
+            if ph.p_type != PT_LOAD {
+                continue;
+            }
+            if ph.filesz > ph.memsz {
+                return Err(ElfError::BadProgramHeader);
+            }
+            match ph.offset.checked_add(ph.filesz) {
+                Some(e) if e <= self.data.len() as u64 => (),
+                _ => return Err(ElfError::SegmentOutOfRange),
+            }
+            let seg_end = match ph.vaddr.checked_add(ph.memsz) {
+                Some(e) if ph.vaddr >= base && e <= end => e,
+                _ => return Err(ElfError::SegmentOutOfRange),
+            };
+            if ph.flags & PF_X != 0 && self.entry >= ph.vaddr && self.entry < seg_end {
+                entry_ok = true;
+            }
+        }
+        if !entry_ok {
+            return Err(ElfError::EntryOutOfRange);
+        }
+        self.check_write_xor_exec()
+    }
+
+    /// Segments which share a page share its mapping, so a writable segment must not touch a page
+    /// of an executable one, itself included. Only valid after the ranges are checked.
+    fn check_write_xor_exec(&self) -> Result<()> {
+        let pages = |ph: &ProgramHeader| {
+            let first = ph.vaddr & !(PAGE_SIZE - 1);
+            let last = (ph.vaddr + ph.memsz - 1) & !(PAGE_SIZE - 1);
+            (first, last)
+        };
+        let loaded = |flag: u32| {
+            (0..self.phnum as usize)
+                .filter_map(move |i| self.program_header(i).ok())
+                .filter(move |ph| ph.p_type == PT_LOAD && ph.memsz != 0 && ph.flags & flag != 0)
+        };
+        for w in loaded(PF_W) {
+            let (w_first, w_last) = pages(&w);
+            for x in loaded(PF_X) {
+                let (x_first, x_last) = pages(&x);
+                if w_first <= x_last && x_first <= w_last {
+                    return Err(ElfError::WritableCode);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `loader::USER_LOAD_BASE` and `USER_LOAD_SIZE`, which the samples are linked for.
+    const BASE: u64 = 0x10_0000_0000;
+    const SIZE: u64 = 0x3000_0000;
+
+    const HELLO: &[u8] = include_bytes!("../user/hello.elf");
+
+    fn check(image: &[u8]) -> Result<()> {
+        Elf::parse(image)?.check_layout(BASE, SIZE)
+    }
+
+    fn put_u32(image: &mut [u8], off: usize, v: u32) {
+        image[off..off + 4].copy_from_slice(&v.to_le_bytes());
+    }
+
+    fn put_u64(image: &mut [u8], off: usize, v: u64) {
+        image[off..off + 8].copy_from_slice(&v.to_le_bytes());
+    }
+
+    /// Offset of program header `i` of `image`.
+    fn phdr(image: &[u8], i: usize) -> usize {
+        u64_at(image, 0x20).unwrap() as usize + i * PHDR_SIZE
+    }
+
+    /// Index of the first program header of type `p_type`.
+    fn find(image: &[u8], p_type: u32) -> usize {
+        let elf = Elf::parse(image).unwrap();
+        (0..elf.phnum as usize)
+            .find(|&i| elf.program_header(i).unwrap().p_type == p_type)
+            .unwrap()
+    }
+
+    #[test]
+    fn accepts_static_executable() {
+        let elf = Elf::parse(HELLO).unwrap();
+        assert_eq!(elf.entry(), BASE);
+        elf.check_layout(BASE, SIZE).unwrap();
+
+        let segments: Vec<_> = elf.segments().collect();
+        assert_eq!(segments.len(), 3);
+        let (text, bytes) = segments[0];
+        assert_eq!(text.vaddr, BASE);
+        assert_eq!(text.flags & (PF_X | PF_W), PF_X);
+        assert_eq!(bytes.len() as u64, text.filesz);
+        // .data then .bss
+        let (data, _) = segments[2];
+        assert_eq!(data.flags & PF_W, PF_W);
+        assert!(data.memsz > data.filesz);
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        assert!(matches!(Elf::parse(&HELLO[..32]), Err(ElfError::TooShort)));
+
+        let mut image = HELLO.to_vec();
+        image[1] = b'e';
+        assert!(matches!(Elf::parse(&image), Err(ElfError::BadMagic)));
+
+        let mut image = HELLO.to_vec();
+        image[4] = 1;
+        assert!(matches!(Elf::parse(&image), Err(ElfError::NotElf64)));
+
+        let mut image = HELLO.to_vec();
+        image[0x12] = 62; // x86-64
+        assert!(matches!(Elf::parse(&image), Err(ElfError::NotAarch64)));
+
+        let mut image = HELLO.to_vec();
+        image[0x10] = 1; // ET_REL
+        assert!(matches!(Elf::parse(&image), Err(ElfError::NotExecutable)));
+    }
+
+    #[test]
+    fn rejects_position_independent() {
+        let image = include_bytes!("../user/fixtures/pie.elf");
+        assert!(matches!(Elf::parse(image), Err(ElfError::NeedsRelocation)));
+    }
+
+    #[test]
+    fn rejects_dynamically_linked() {
+        let image = include_bytes!("../user/fixtures/dynamic.elf");
+        assert!(matches!(Elf::parse(image), Err(ElfError::NeedsRelocation)));
+
+        // PT_GNU_STACK turned into the others.
+        let stack = phdr(HELLO, find(HELLO, 0x6474_E551));
+        for &p_type in &[PT_INTERP, PT_DYNAMIC] {
+            let mut image = HELLO.to_vec();
+            put_u32(&mut image, stack, p_type);
+            assert!(matches!(Elf::parse(&image), Err(ElfError::NeedsRelocation)));
+        }
+    }
+
+    #[test]
+    fn rejects_segments_out_of_range() {
+        let image = include_bytes!("../user/fixtures/low.elf");
+        assert!(matches!(check(image), Err(ElfError::SegmentOutOfRange)));
+
+        // past the end of the file.
+        let mut image = HELLO.to_vec();
+        let text = phdr(HELLO, find(HELLO, PT_LOAD));
+        put_u64(&mut image, text + 0x8, HELLO.len() as u64);
+        assert!(matches!(check(&image), Err(ElfError::SegmentOutOfRange)));
+
+        // offset + filesz overflows.
+        put_u64(&mut image, text + 0x8, u64::MAX);
+        assert!(matches!(check(&image), Err(ElfError::SegmentOutOfRange)));
+
+        // vaddr + memsz overflows.
+        let mut image = HELLO.to_vec();
+        put_u64(&mut image, text + 0x10, u64::MAX - 0x10);
+        assert!(matches!(check(&image), Err(ElfError::SegmentOutOfRange)));
+
+        // a load region which ends past the address space.
+        assert!(matches!(
+            Elf::parse(HELLO).unwrap().check_layout(BASE, u64::MAX),
+            Err(ElfError::SegmentOutOfRange)
+        ));
+    }
+
+    #[test]
+    fn rejects_filesz_above_memsz() {
+        let mut image = HELLO.to_vec();
+        let data = phdr(HELLO, 2);
+        let memsz = u64_at(HELLO, data as u64 + 0x28).unwrap();
+        put_u64(&mut image, data + 0x20, memsz + 1);
+        assert!(matches!(check(&image), Err(ElfError::BadProgramHeader)));
+    }
+
+    #[test]
+    fn rejects_bad_entry() {
+        let image = include_bytes!("../user/fixtures/bad_entry.elf");
+        assert!(matches!(check(image), Err(ElfError::EntryOutOfRange)));
+
+        let mut image = HELLO.to_vec();
+        put_u64(&mut image, 0x18, BASE + SIZE);
+        assert!(matches!(check(&image), Err(ElfError::EntryOutOfRange)));
+    }
+
+    #[test]
+    fn rejects_writable_code() {
+        let image = include_bytes!("../user/fixtures/wx.elf");
+        assert!(matches!(check(image), Err(ElfError::WritableCode)));
+
+        // one segment both writable and executable.
+        let mut image = HELLO.to_vec();
+        let text = phdr(HELLO, find(HELLO, PT_LOAD));
+        put_u32(&mut image, text + 0x4, PF_X | PF_W | 0x4);
+        assert!(matches!(check(&image), Err(ElfError::WritableCode)));
+    }
+
+    #[test]
+    fn header_offsets_do_not_overflow() {
+        let mut image = HELLO.to_vec();
+        put_u64(&mut image, 0x20, u64::MAX - 8);
+        assert!(Elf::parse(&image).is_err());
+
+        let mut image = HELLO.to_vec();
+        put_u64(&mut image, 0x28, u64::MAX - 8);
+        assert!(matches!(Elf::parse(&image), Err(ElfError::TooShort)));
+
+        assert!(matches!(
+            u64_at(HELLO, u64::MAX - 1),
+            Err(ElfError::TooShort)
+        ));
+    }
+}
@@ -0,0 +1,112 @@
+//! Events set from IRQ handlers and taken from thread context.
+//!
+//! Updates go through `sync::update_u32`, which works before the caches are on.
+
+use crate::sync::{critical_section, update_u32};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// Up to 32 independent flags, e.g. one per channel.
+pub struct EventFlags {
+    bits: AtomicU32,
+    /// Number of `set` calls which found the flag already set.
+    coalesced: AtomicU32,
+}
+
+#[allow(dead_code)]
+impl EventFlags {
+    pub const fn new() -> EventFlags {
+        EventFlags {
+            bits: AtomicU32::new(0),
+            coalesced: AtomicU32::new(0),
+        }
+    }
+
+    /// Set flags in `mask`. Callable from IRQ context.
+    pub fn set(&self, mask: u32) {
+        let prev = update_u32(&self.bits, |v| v | mask);
+        let again = prev & mask;
+        if again != 0 {
+            update_u32(&self.coalesced, |v| v.wrapping_add(again.count_ones()));
+        }
+    }
+
+    /// Clear flags in `mask` and return which of them were set.
+    pub fn take(&self, mask: u32) -> u32 {
+        update_u32(&self.bits, |v| v & !mask) & mask
+    }
+
+    pub fn peek(&self, mask: u32) -> u32 {
+        self.bits.load(Ordering::Acquire) & mask
+    }
+
+    pub fn clear(&self, mask: u32) {
+        update_u32(&self.bits, |v| v & !mask);
+    }
+
+    /// Events lost by being set twice before taken, since the last call.
+    pub fn take_coalesced(&self) -> u32 {
+        update_u32(&self.coalesced, |_| 0)
+    }
+
+    /// Sleep with wfi until any flag in `mask` is set, then take them.
+    pub fn wait(&self, mask: u32) -> u32 {
+        loop {
+            // check and sleep with IRQ masked, or a wake up may slip in between.
+            // wfi still returns on a pending IRQ, which is taken on restore.
+            let v = critical_section(|| {
+                let v = self.take(mask);
+                if v == 0 {
+                    unsafe { raspi3_boot::wfi() };
+                }
+                v
+            });
+            if v != 0 {
+                return v;
+            }
+        }
+    }
+}
+
+/// Counts every occurrence, so nothing is lost when it fires twice before taken.
+pub struct EventCounter {
+    count: AtomicU32,
+}
+
+#[allow(dead_code)]
+impl EventCounter {
+    pub const fn new() -> EventCounter {
+        EventCounter {
+            count: AtomicU32::new(0),
+        }
+    }
+
+    /// Callable from IRQ context.
+    pub fn signal(&self) {
+        update_u32(&self.count, |v| v.wrapping_add(1));
+    }
+
+    /// Return the number of occurrences since the last call.
+    pub fn take(&self) -> u32 {
+        update_u32(&self.count, |_| 0)
+    }
+
+    pub fn peek(&self) -> u32 {
+        self.count.load(Ordering::Acquire)
+    }
+
+    /// Sleep with wfi until signalled, then take the count.
+    pub fn wait(&self) -> u32 {
+        loop {
+            let v = critical_section(|| {
+                let v = self.take();
+                if v == 0 {
+                    unsafe { raspi3_boot::wfi() };
+                }
+                v
+            });
+            if v != 0 {
+                return v;
+            }
+        }
+    }
+}
@@ -4,8 +4,16 @@
 
 //! Exception handling.
 
+use crate::interrupt::IrqSource;
+use crate::irq_dispatch;
+use crate::irq_stats;
+use crate::local_intc;
+use crate::mmu;
 use crate::optional_cell::OptionalCell;
-use cortex_a::{asm, barrier, regs::*};
+use crate::stacks;
+use crate::syscall;
+use crate::thread;
+use cortex_a::{barrier, regs::*};
 use register::mmio::ReadWrite;
 
 // Assembly counterpart to this file.
@@ -17,7 +25,7 @@ struct SpsrEL1(ReadWrite<u32, SPSR_EL1::Register>);
 
 /// The exception context as it is stored on the stack on exception entry.
 #[repr(C)]
-struct ExceptionContext {
+pub struct ExceptionContext {
     // General Purpose Registers.
     gpr: [u64; 30],
     // The link register, aka x30.
@@ -26,41 +34,49 @@ struct ExceptionContext {
     elr_el1: u64,
     // Saved program status.
     spsr_el1: SpsrEL1,
+    // Stack pointer of EL0.
+    sp_el0: u64,
+    // Non-zero if the thread uses FP/SIMD and the registers below are valid.
+    fp_saved: u64,
+    __reserved: u64,
+    fpsr: u64,
+    fpcr: u64,
+    q: [u128; 32],
 }
 
-pub trait InterruptionSource {
-    fn on_interruption(&self, id: u32);
-}
-
-pub struct IrqHandler {
-    device: &'static dyn InterruptionSource,
-    int_no: &'static [u32],
-}
+impl ExceptionContext {
+    pub fn gpr(&self, n: usize) -> u64 {
+        match n {
+            0..=29 => self.gpr[n],
+            30 => self.lr,
+            _ => 0,
+        }
+    }
 
-impl IrqHandler {
-    pub fn new(device: &'static dyn InterruptionSource, int_no: &'static [u32]) -> IrqHandler {
-        IrqHandler { device, int_no }
+    pub fn set_gpr(&mut self, n: usize, v: u64) {
+        match n {
+            0..=29 => self.gpr[n] = v,
+            30 => self.lr = v,
+            _ => (),
+        }
     }
-}
 
-pub struct IrqHandlersSettings {
-    pub irq_devices: &'static [IrqHandler],
-    pub basic_irq_devices: &'static [IrqHandler],
-}
+    pub fn elr(&self) -> u64 {
+        self.elr_el1
+    }
 
-impl IrqHandlersSettings {
-    pub fn new(
-        irq_devices: &'static [IrqHandler],
-        basic_irq_devices: &'static [IrqHandler],
-    ) -> IrqHandlersSettings {
-        IrqHandlersSettings {
-            irq_devices,
-            basic_irq_devices,
-        }
+    /// Give FP/SIMD to the thread after its first use trapped, starting from zeroed registers.
+    fn start_fp(&mut self) {
+        self.fpsr = 0;
+        self.fpcr = 0;
+        self.q = [0; 32];
+        self.fp_saved = 1;
     }
 }
 
-static mut DEVICES: Option<&'static IrqHandlersSettings> = None;
+pub trait InterruptionSource {
+    fn on_interruption(&self, source: IrqSource);
+}
 
 pub trait ConsoleOut {
     fn puts(&self, s: &str);
@@ -79,23 +95,54 @@ impl DebugContext {
 
 static mut DEBUG_CONTEXT: Option<&'static DebugContext> = None;
 
+/// Size of the context on the stack. See exception.S.
+const CONTEXT_SIZE: u64 = 16 * 51;
+
+/// ESR_EL1.EC of `svc` from AArch64.
+const EC_SVC64: u64 = 0x15;
+/// ESR_EL1.EC of FP/SIMD access trapped by CPACR_EL1.
+const EC_FP_ACCESS: u64 = 0x07;
+
+/// Nesting level of IRQ handlers on each core. Threads are switched only at level 0.
+static mut IRQ_DEPTH: [u32; local_intc::CORES] = [0; local_intc::CORES];
+
+fn esr_el1() -> u64 {
+    let esr: u64;
+    unsafe { asm!("mrs $0, esr_el1" : "=r"(esr) ::: "volatile") };
+    esr
+}
+
+fn far_el1() -> u64 {
+    let far: u64;
+    unsafe { asm!("mrs $0, far_el1" : "=r"(far) ::: "volatile") };
+    far
+}
+
 //--------------------------------------------------------------------------------------------------
 // Exception vector implementation
 //--------------------------------------------------------------------------------------------------
+// Nothing here allocates: handlers run on every core, also while the heap is in use.
 unsafe fn puts(s: &str) {
-    let s2 = ["[Exception] ", s].concat();
-    DEBUG_CONTEXT.unwrap().callback.map(|c| c.puts(&s2));
+    DEBUG_CONTEXT.unwrap().callback.map(|c| {
+        c.puts("[Exception] ");
+        c.puts(s)
+    });
 }
 
-unsafe fn puts2(s: &str) {
-    let s2 = ["[Exception] ", s].concat();
-    DEBUG_CONTEXT.unwrap().callback.map(|c| c.puts(&s2));
+/// Continue the line without the prefix.
+unsafe fn puts_cont(s: &str) {
+    DEBUG_CONTEXT.unwrap().callback.map(|c| c.puts(s));
 }
 
 unsafe fn hex(v: u32) {
     DEBUG_CONTEXT.unwrap().callback.map(|c| c.hex(v));
 }
 
+unsafe fn hex64(v: u64) {
+    hex((v >> 32) as u32);
+    hex(v as u32);
+}
+
 unsafe fn hexln(v: u32) {
     DEBUG_CONTEXT.unwrap().callback.map(|c| c.hex(v));
     DEBUG_CONTEXT.unwrap().callback.map(|c| c.puts("\n"));
@@ -121,43 +168,50 @@ fn irq_handler(e: &ExceptionContext) {
 
         if int.is_any_irq_pending() {
             let pend = int.get_raw_pending();
-            puts2("IRQ pending: ");
+            puts("IRQ pending: ");
             hex((pend & 0xFFFF_FFFF) as u32);
             puts(" ");
             hexln(((pend >> 32) & 0xFFFF_FFFF) as u32);
-            for id in 0..63 {
-                if (pend & (1 << id)) != 0 {
-                    let devs = DEVICES.unwrap().irq_devices;
-                    for d in devs.iter() {
-                        if d.int_no.contains(&id) {
-                            puts("  from device: ");
-                            hexln(id);
-                            d.device.on_interruption(id);
-                        }
-                    }
-                }
-            }
         } else {
             let pend = int.get_raw_basic_pending();
             if pend != 0 {
                 puts("Basic IRQ pending: ");
                 hexln(pend);
-                for id in 0..7 {
-                    if (pend & (1 << id)) != 0 {
-                        let devs = DEVICES.unwrap().basic_irq_devices;
-                        for d in devs.iter() {
-                            if d.int_no.contains(&id) {
-                                puts("  from device: ");
-                                hexln(id);
-                                d.device.on_interruption(id);
-                            }
-                        }
-                    }
-                }
-            } else {
-                puts("Some unknown case...\n");
             }
         }
+
+        if irq_dispatch::handle_local_pending() == 0 {
+            irq_stats::record_spurious();
+            puts("Some unknown case...\n");
+        }
+    }
+}
+
+/// Serve IRQ and pick the context to return to. Threads are switched on core 0 only,
+/// when leaving the outermost handler.
+unsafe fn irq_entry(e: &mut ExceptionContext) -> *mut ExceptionContext {
+    let core = local_intc::core_id();
+    IRQ_DEPTH[core] += 1;
+    irq_handler(e);
+    IRQ_DEPTH[core] -= 1;
+
+    if core == 0 && IRQ_DEPTH[core] == 0 {
+        thread::switch(e, false)
+    } else {
+        e
+    }
+}
+
+/// Low latency path: no printing here, but for a FIQ nobody serves. Locks mask FIQ, so one
+/// never preempts a holder on this core.
+fn fiq_handler(e: &ExceptionContext) {
+    if !irq_dispatch::dispatch_fiq() {
+        // source is still asserted. mask it to avoid looping forever.
+        crate::interrupt::Interrupt::new().disable_fiq();
+        unsafe {
+            puts("FIQ without handler from 0x");
+            hexln(e.elr_el1 as u32);
+        }
     }
 }
 
@@ -175,6 +229,11 @@ unsafe extern "C" fn current_el0_irq(e: &mut ExceptionContext) {
     default_exception_handler(e);
 }
 
+#[no_mangle]
+unsafe extern "C" fn current_el0_fiq(e: &mut ExceptionContext) {
+    default_exception_handler(e);
+}
+
 #[no_mangle]
 unsafe extern "C" fn current_el0_serror(e: &mut ExceptionContext) {
     default_exception_handler(e);
@@ -185,14 +244,70 @@ unsafe extern "C" fn current_el0_serror(e: &mut ExceptionContext) {
 //--------------------------------------------------------------------------------------------------
 
 /// Asynchronous exception taken from the current EL, using SP of the current EL.
+/// `svc` here is a request from the running thread to switch.
 #[no_mangle]
-unsafe extern "C" fn current_elx_synchronous(e: &mut ExceptionContext) {
+unsafe extern "C" fn current_elx_synchronous(e: &mut ExceptionContext) -> *mut ExceptionContext {
+    let esr = esr_el1();
+    if (esr >> 26) & 0x3F == EC_SVC64 && local_intc::core_id() == 0 {
+        return thread::switch(e, true);
+    }
+    if (esr >> 26) & 0x3F == EC_FP_ACCESS {
+        e.start_fp();
+        return e;
+    }
+    if mmu::describe_abort(esr).is_some() && report_stack_overflow(e, far_el1()) {
+        halt();
+    }
     default_exception_handler(e);
+    e
 }
 
+/// The context did not fit on the stack, so the vector switched to the emergency stack.
 #[no_mangle]
-unsafe extern "C" fn current_elx_irq(e: &mut ExceptionContext) {
-    irq_handler(e);
+unsafe extern "C" fn current_elx_stack_overflow(e: &mut ExceptionContext) {
+    if !report_stack_overflow(e, far_el1()) {
+        puts("Stack overflow on an unknown stack. ELR: 0x");
+        hex64(e.elr_el1);
+        puts_cont(" FAR: 0x");
+        hex64(far_el1());
+        puts_cont("\n");
+    }
+    halt();
+}
+
+/// Report an access to a guard page at `far`. False if it is not one.
+unsafe fn report_stack_overflow(e: &ExceptionContext, far: u64) -> bool {
+    if let Some(owner) = stacks::guard_owner(far) {
+        puts("Stack overflow: ");
+        puts_cont(owner);
+    } else if let Some(name) = thread::guard_owner(far) {
+        puts("Stack overflow: thread ");
+        puts_cont(name);
+    } else {
+        return false;
+    }
+    puts_cont(". ELR: 0x");
+    hex64(e.elr_el1);
+    puts_cont(" FAR: 0x");
+    hex64(far);
+    puts_cont("\n");
+    true
+}
+
+fn halt() -> ! {
+    loop {
+        unsafe { raspi3_boot::wfe() };
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn current_elx_irq(e: &mut ExceptionContext) -> *mut ExceptionContext {
+    irq_entry(e)
+}
+
+#[no_mangle]
+unsafe extern "C" fn current_elx_fiq(e: &mut ExceptionContext) {
+    fiq_handler(e);
 }
 
 #[no_mangle]
@@ -204,14 +319,50 @@ unsafe extern "C" fn current_elx_serror(e: &mut ExceptionContext) {
 // Lower, AArch64
 //--------------------------------------------------------------------------------------------------
 
+/// System call or fault of a thread running at EL0.
 #[no_mangle]
-unsafe extern "C" fn lower_aarch64_synchronous(e: &mut ExceptionContext) {
-    default_exception_handler(e);
+unsafe extern "C" fn lower_aarch64_synchronous(e: &mut ExceptionContext) -> *mut ExceptionContext {
+    let esr = esr_el1();
+    if (esr >> 26) & 0x3F == EC_SVC64 {
+        return syscall::dispatch(e);
+    }
+    if (esr >> 26) & 0x3F == EC_FP_ACCESS {
+        e.start_fp();
+        return e;
+    }
+
+    // the kernel is fine. only the thread goes away.
+    puts("EL0 fault in thread ");
+    puts_cont(thread::name(thread::current()).unwrap_or("?"));
+    if let Some((kind, level, write)) = mmu::describe_abort(esr) {
+        puts_cont(". Page fault: ");
+        puts_cont(kind);
+        puts_cont(", level ");
+        puts_cont(["0", "1", "2", "3"][level as usize]);
+        puts_cont(match write {
+            Some(true) => ", on write",
+            Some(false) => ", on read",
+            None => ", on fetch",
+        });
+    }
+    puts_cont(". ESR: 0x");
+    hex(esr as u32);
+    puts_cont(" ELR: 0x");
+    hex64(e.elr_el1);
+    puts_cont(" FAR: 0x");
+    hex64(far_el1());
+    puts_cont("\n");
+    thread::exit_from(e)
 }
 
 #[no_mangle]
-unsafe extern "C" fn lower_aarch64_irq(e: &mut ExceptionContext) {
-    default_exception_handler(e);
+unsafe extern "C" fn lower_aarch64_irq(e: &mut ExceptionContext) -> *mut ExceptionContext {
+    irq_entry(e)
+}
+
+#[no_mangle]
+unsafe extern "C" fn lower_aarch64_fiq(e: &mut ExceptionContext) {
+    fiq_handler(e);
 }
 
 #[no_mangle]
@@ -233,6 +384,11 @@ unsafe extern "C" fn lower_aarch32_irq(e: &mut ExceptionContext) {
     default_exception_handler(e);
 }
 
+#[no_mangle]
+unsafe extern "C" fn lower_aarch32_fiq(e: &mut ExceptionContext) {
+    default_exception_handler(e);
+}
+
 #[no_mangle]
 unsafe extern "C" fn lower_aarch32_serror(e: &mut ExceptionContext) {
     default_exception_handler(e);
@@ -263,35 +419,55 @@ pub unsafe fn set_vbar_el1() -> u64 {
     addr
 }
 
-#[inline(always)]
-pub unsafe fn el2_to_el1_transition(addr: u64) -> ! {
-    // Set EL1 execution state to AArch64.
-    HCR_EL2.write(HCR_EL2::RW::EL1IsAarch64);
-
-    // Set up a simulated exception return.
-    //
-    // First, fake a saved program status, where all interrupts were masked and SP_EL1 was used as a
-    // stack pointer.
-    SPSR_EL2.write(
-        SPSR_EL2::D::Masked
-            + SPSR_EL2::A::Masked
-            + SPSR_EL2::I::Masked
-            + SPSR_EL2::F::Masked
-            + SPSR_EL2::M::EL1h,
+/// Lay out a context below `stack_top` which returns to `entry(arg)` in EL1h with IRQ and FIQ
+/// unmasked.
+///
+/// # Safety
+///
+/// - At least `CONTEXT_SIZE` bytes below `stack_top` must be writable.
+pub unsafe fn new_thread_context(stack_top: u64, entry: u64, arg: u64) -> *mut ExceptionContext {
+    let e = ((stack_top & !0xF) - CONTEXT_SIZE) as *mut ExceptionContext;
+    core::ptr::write_bytes(e as *mut u8, 0, CONTEXT_SIZE as usize);
+
+    (*e).gpr[0] = arg;
+    (*e).elr_el1 = entry;
+    (*e).spsr_el1.0.write(
+        SPSR_EL1::D::Masked
+            + SPSR_EL1::A::Masked
+            + SPSR_EL1::I::Unmasked
+            + SPSR_EL1::F::Unmasked
+            + SPSR_EL1::M::EL1h,
     );
+    e
+}
 
-    // Second, let the link register point to init().
-    ELR_EL2.set(addr);
-
-    // Set up SP_EL1 (stack pointer), which will be used by EL1 once we "return" to it.
-    SP_EL1.set(0x80000);
-
-    // Use `eret` to "return" to EL1. This will result in execution of `reset()` in EL1.
-    asm::eret()
+/// Same as `new_thread_context`, but returns to EL0 with `user_sp` as its stack.
+/// `stack_top` is the kernel stack the thread uses while in exceptions.
+///
+/// # Safety
+///
+/// - Same as `new_thread_context`.
+pub unsafe fn new_user_context(
+    stack_top: u64,
+    entry: u64,
+    arg: u64,
+    user_sp: u64,
+) -> *mut ExceptionContext {
+    let e = new_thread_context(stack_top, entry, arg);
+    (*e).sp_el0 = user_sp & !0xF;
+    (*e).spsr_el1.0.write(
+        SPSR_EL1::D::Masked
+            + SPSR_EL1::A::Masked
+            + SPSR_EL1::I::Unmasked
+            + SPSR_EL1::F::Unmasked
+            + SPSR_EL1::M::EL0t,
+    );
+    e
 }
 
-pub unsafe fn set_irq_handlers(h: &'static IrqHandlersSettings) -> bool {
-    (*DEVICES.get_or_insert(h)) as *const _ == h
+/// Whether this core is running an IRQ handler.
+pub fn in_irq() -> bool {
+    unsafe { IRQ_DEPTH[local_intc::core_id()] != 0 }
 }
 
 pub unsafe fn set_debug_context(c: &'static DebugContext) -> bool {
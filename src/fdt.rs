@@ -0,0 +1,786 @@
+//! Flattened device tree passed by the firmware. Read in place, nothing is allocated.
+//!
+//! All values in the blob are big endian.
+
+const MAGIC: u32 = 0xD00D_FEED;
+/// Oldest version with the layout read here.
+const MIN_VERSION: u32 = 16;
+const HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+
+/// Default #address-cells and #size-cells when a node does not give them.
+const DEFAULT_ADDRESS_CELLS: u32 = 2;
+const DEFAULT_SIZE_CELLS: u32 = 1;
+/// Largest #address-cells or #size-cells taken. Bigger ones end `reg` and `ranges` early.
+const MAX_CELLS: u32 = 4;
+
+#[derive(Debug)]
+pub enum FdtError {
+    BadMagic,
+    BadVersion,
+    /// A block lies outside `totalsize`.
+    Truncated,
+}
+pub type Result<T> = ::core::result::Result<T, FdtError>;
+
+/// A range of physical memory from `reg` or the reservation block.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Region {
+    pub base: u64,
+    pub size: u64,
+}
+
+#[allow(dead_code)]
+impl Region {
+    pub fn end(&self) -> u64 {
+        self.base.saturating_add(self.size)
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Fdt<'a> {
+    data: &'a [u8],
+    structs: &'a [u8],
+    strings: &'a [u8],
+    mem_rsvmap: usize,
+}
+
+fn be32(data: &[u8], off: usize) -> Option<u32> {
+    let b = data.get(off..off + 4)?;
+    Some(u32::from(b[0]) << 24 | u32::from(b[1]) << 16 | u32::from(b[2]) << 8 | u32::from(b[3]))
+}
+
+fn be64(data: &[u8], off: usize) -> Option<u64> {
+    Some(u64::from(be32(data, off)?) << 32 | u64::from(be32(data, off + 4)?))
+}
+
+/// Value of `cells` 32 bit cells at `off`. More than 2 cells keep the low 64 bits.
+fn read_cells(data: &[u8], off: usize, cells: u32) -> Option<u64> {
+    let mut v = 0u64;
+    for i in 0..cells as usize {
+        v = v << 32 | u64::from(be32(data, off + i * 4)?);
+    }
+    Some(v)
+}
+
+fn align4(off: usize) -> usize {
+    (off + 3) & !3
+}
+
+/// NUL terminated string at `off`.
+fn cstr(data: &[u8], off: usize) -> Option<&str> {
+    let rest = data.get(off..)?;
+    let len = rest.iter().position(|&b| b == 0)?;
+    core::str::from_utf8(&rest[..len]).ok()
+}
+
+#[allow(dead_code)]
+impl<'a> Fdt<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Fdt<'a>> {
+        if be32(data, 0) != Some(MAGIC) {
+            return Err(FdtError::BadMagic);
+        }
+        let field = |i: usize| be32(data, i * 4).ok_or(FdtError::Truncated);
+        let total = field(1)? as usize;
+        let off_struct = field(2)? as usize;
+        let off_strings = field(3)? as usize;
+        let off_rsvmap = field(4)? as usize;
+        if field(5)? < MIN_VERSION {
+            return Err(FdtError::BadVersion);
+        }
+        let size_strings = field(8)? as usize;
+        let size_struct = field(9)? as usize;
+
+        let data = data.get(..total).ok_or(FdtError::Truncated)?;
+        let block = |off: usize, size: usize| {
+            off.checked_add(size)
+                .and_then(|end| data.get(off..end))
+                .ok_or(FdtError::Truncated)
+        };
+        Ok(Fdt {
+            data,
+            structs: block(off_struct, size_struct)?,
+            strings: block(off_strings, size_strings)?,
+            mem_rsvmap: off_rsvmap,
+        })
+    }
+
+    /// # Safety
+    ///
+    /// - A blob must be at `addr`, or at least HEADER_SIZE readable bytes. It must stay there.
+    pub unsafe fn from_addr(addr: u64) -> Result<Fdt<'static>> {
+        let header = core::slice::from_raw_parts(addr as *const u8, HEADER_SIZE);
+        if be32(header, 0) != Some(MAGIC) {
+            return Err(FdtError::BadMagic);
+        }
+        let total = be32(header, 4).ok_or(FdtError::Truncated)? as usize;
+        Fdt::new(core::slice::from_raw_parts(addr as *const u8, total))
+    }
+
+    /// Where the blob itself is, so it is not overwritten.
+    pub fn region(&self) -> Region {
+        Region {
+            base: self.data.as_ptr() as u64,
+            size: self.data.len() as u64,
+        }
+    }
+
+    pub fn root(&self) -> Option<Node<'a>> {
+        let mut off = 0;
+        loop {
+            match be32(self.structs, off)? {
+                FDT_NOP => off += 4,
+                FDT_BEGIN_NODE => {
+                    return Node::at(*self, off, DEFAULT_ADDRESS_CELLS, DEFAULT_SIZE_CELLS)
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    /// Node at `path` such as "/soc/serial@7e201000". Components without a unit address
+    /// also match nodes with one, "/memory" finds "memory@0".
+    pub fn find(&self, path: &str) -> Option<Node<'a>> {
+        let mut node = self.root()?;
+        for comp in path.split('/').filter(|c| !c.is_empty()) {
+            node = node.children().find(|c| c.matches(comp))?;
+        }
+        Some(node)
+    }
+
+    /// First node, in tree order, with `compat` in its compatible list.
+    pub fn find_compatible(&self, compat: &str) -> Option<Node<'a>> {
+        self.root()?.descendants().find(|n| n.is_compatible(compat))
+    }
+
+    /// RAM from the memory nodes.
+    pub fn memory(&self) -> impl Iterator<Item = Region> + 'a {
+        self.root()
+            .into_iter()
+            .flat_map(|r| r.children())
+            .filter(|n| n.matches("memory") || n.property_str("device_type") == Some("memory"))
+            .flat_map(|n| n.reg())
+    }
+
+    /// Entries of the memory reservation block, then the children of /reserved-memory.
+    pub fn reservations(&self) -> impl Iterator<Item = Region> + 'a {
+        let data = self.data;
+        let rsvmap = self.mem_rsvmap;
+        let block = (0..)
+            .map(move |i| {
+                let off = rsvmap + i * 16;
+                Region {
+                    base: be64(data, off).unwrap_or(0),
+                    size: be64(data, off + 8).unwrap_or(0),
+                }
+            })
+            .take_while(|r| r.size != 0);
+        let nodes = self
+            .find("/reserved-memory")
+            .into_iter()
+            .flat_map(|n| n.children())
+            .flat_map(|n| n.reg());
+        block.chain(nodes)
+    }
+
+    /// Command line from /chosen.
+    pub fn bootargs(&self) -> Option<&'a str> {
+        self.find("/chosen")?.property_str("bootargs")
+    }
+
+    /// CPU address of `bus_addr` as seen by children of /soc, through its `ranges`.
+    pub fn soc_to_cpu(&self, bus_addr: u64) -> Option<u64> {
+        let soc = self.find("/soc")?;
+        soc.ranges()
+            .find(|&(child, _, size)| bus_addr >= child && bus_addr - child < size)
+            .map(|(child, parent, _)| parent + (bus_addr - child))
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Node<'a> {
+    fdt: Fdt<'a>,
+    name: &'a str,
+    /// First token after the name.
+    body: usize,
+    /// Cells of `reg` in this node, given by the parent.
+    address_cells: u32,
+    size_cells: u32,
+}
+
+#[derive(Copy, Clone)]
+pub struct Property<'a> {
+    pub name: &'a str,
+    pub value: &'a [u8],
+}
+
+#[allow(dead_code)]
+impl<'a> Node<'a> {
+    /// Node whose FDT_BEGIN_NODE is at `off`.
+    fn at(fdt: Fdt<'a>, off: usize, address_cells: u32, size_cells: u32) -> Option<Node<'a>> {
+        let name = cstr(fdt.structs, off + 4)?;
+        Some(Node {
+            fdt,
+            name,
+            body: align4(off + 4 + name.len() + 1),
+            address_cells,
+            size_cells,
+        })
+    }
+
+    /// Name with the unit address, "" for the root.
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    fn matches(&self, comp: &str) -> bool {
+        self.name == comp || (!comp.contains('@') && self.name.split('@').next() == Some(comp))
+    }
+
+    pub fn properties(&self) -> Properties<'a> {
+        Properties {
+            fdt: self.fdt,
+            off: self.body,
+        }
+    }
+
+    pub fn property(&self, name: &str) -> Option<&'a [u8]> {
+        self.properties().find(|p| p.name == name).map(|p| p.value)
+    }
+
+    /// Property holding a single string.
+    pub fn property_str(&self, name: &str) -> Option<&'a str> {
+        let v = self.property(name)?;
+        let v = match v.last() {
+            Some(0) => &v[..v.len() - 1],
+            _ => v,
+        };
+        core::str::from_utf8(v).ok()
+    }
+
+    pub fn property_u32(&self, name: &str) -> Option<u32> {
+        be32(self.property(name)?, 0)
+    }
+
+    /// Strings of the compatible list.
+    pub fn compatible(&self) -> impl Iterator<Item = &'a str> {
+        self.property("compatible")
+            .unwrap_or(&[])
+            .split(|&b| b == 0)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| core::str::from_utf8(s).ok())
+    }
+
+    pub fn is_compatible(&self, compat: &str) -> bool {
+        self.compatible().any(|c| c == compat)
+    }
+
+    /// Cells children use for their `reg`.
+    fn child_cells(&self) -> (u32, u32) {
+        (
+            self.property_u32("#address-cells")
+                .unwrap_or(DEFAULT_ADDRESS_CELLS),
+            self.property_u32("#size-cells")
+                .unwrap_or(DEFAULT_SIZE_CELLS),
+        )
+    }
+
+    pub fn children(&self) -> Children<'a> {
+        let (address_cells, size_cells) = self.child_cells();
+        // children come after all properties.
+        let mut props = self.properties();
+        while props.next().is_some() {}
+        Children {
+            fdt: self.fdt,
+            off: Some(props.off),
+            address_cells,
+            size_cells,
+        }
+    }
+
+    /// This node's subtree in tree order, without itself.
+    pub fn descendants(&self) -> Descendants<'a> {
+        let mut d = Descendants {
+            stack: [None; MAX_DEPTH],
+            depth: 0,
+        };
+        d.stack[0] = Some(self.children());
+        d
+    }
+
+    /// (address, size) pairs of `reg`, in the parent's address space.
+    pub fn reg(&self) -> Reg<'a> {
+        Reg {
+            value: self.property("reg").unwrap_or(&[]),
+            off: 0,
+            address_cells: self.address_cells,
+            size_cells: self.size_cells,
+        }
+    }
+
+    /// (child address, parent address, size) triples of `ranges`.
+    pub fn ranges(&self) -> Ranges<'a> {
+        let (child_cells, size_cells) = self.child_cells();
+        Ranges {
+            value: self.property("ranges").unwrap_or(&[]),
+            off: 0,
+            child_cells,
+            parent_cells: self.address_cells,
+            size_cells,
+        }
+    }
+}
+
+pub struct Properties<'a> {
+    fdt: Fdt<'a>,
+    /// Next token.
+    off: usize,
+}
+
+impl<'a> Iterator for Properties<'a> {
+    type Item = Property<'a>;
+
+    fn next(&mut self) -> Option<Property<'a>> {
+        let s = self.fdt.structs;
+        loop {
+            match be32(s, self.off)? {
+                FDT_NOP => self.off += 4,
+                FDT_PROP => {
+                    let len = be32(s, self.off + 4)? as usize;
+                    let name = cstr(self.fdt.strings, be32(s, self.off + 8)? as usize)?;
+                    let value = s.get(self.off + 12..self.off + 12 + len)?;
+                    self.off = align4(self.off + 12 + len);
+                    return Some(Property { name, value });
+                }
+                _ => return None,
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Children<'a> {
+    fdt: Fdt<'a>,
+    /// Next token. None once the parent's FDT_END_NODE is reached.
+    off: Option<usize>,
+    address_cells: u32,
+    size_cells: u32,
+}
+
+/// Offset after the FDT_END_NODE closing the node whose FDT_BEGIN_NODE is at `off`.
+fn skip_node(s: &[u8], mut off: usize) -> Option<usize> {
+    let mut depth = 0;
+    loop {
+        match be32(s, off)? {
+            FDT_BEGIN_NODE => {
+                depth += 1;
+                off = align4(off + 4 + cstr(s, off + 4)?.len() + 1);
+            }
+            FDT_END_NODE => {
+                depth -= 1;
+                off += 4;
+                if depth == 0 {
+                    return Some(off);
+                }
+            }
+            FDT_PROP => off = align4(off + 12 + be32(s, off + 4)? as usize),
+            FDT_NOP => off += 4,
+            _ => return None,
+        }
+    }
+}
+
+impl<'a> Iterator for Children<'a> {
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Node<'a>> {
+        let s = self.fdt.structs;
+        let mut off = self.off?;
+        loop {
+            match be32(s, off) {
+                Some(FDT_NOP) => off += 4,
+                Some(FDT_BEGIN_NODE) => {
+                    self.off = skip_node(s, off);
+                    return Node::at(self.fdt, off, self.address_cells, self.size_cells);
+                }
+                // FDT_END_NODE of the parent, FDT_END or garbage.
+                _ => {
+                    self.off = None;
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// Deeper nodes are not visited.
+const MAX_DEPTH: usize = 16;
+
+pub struct Descendants<'a> {
+    stack: [Option<Children<'a>>; MAX_DEPTH],
+    depth: usize,
+}
+
+impl<'a> Iterator for Descendants<'a> {
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Node<'a>> {
+        loop {
+            let next = self.stack[self.depth].as_mut().and_then(|c| c.next());
+            match next {
+                Some(n) => {
+                    if self.depth + 1 < MAX_DEPTH {
+                        self.depth += 1;
+                        self.stack[self.depth] = Some(n.children());
+                    }
+                    return Some(n);
+                }
+                None if self.depth == 0 => return None,
+                None => {
+                    self.stack[self.depth] = None;
+                    self.depth -= 1;
+                }
+            }
+        }
+    }
+}
+
+pub struct Reg<'a> {
+    value: &'a [u8],
+    off: usize,
+    address_cells: u32,
+    size_cells: u32,
+}
+
+impl<'a> Iterator for Reg<'a> {
+    type Item = Region;
+
+    fn next(&mut self) -> Option<Region> {
+        if self.address_cells > MAX_CELLS || self.size_cells > MAX_CELLS {
+            return None;
+        }
+        if self.address_cells + self.size_cells == 0 {
+            return None;
+        }
+        let base = read_cells(self.value, self.off, self.address_cells)?;
+        let size_off = self.off + self.address_cells as usize * 4;
+        let size = read_cells(self.value, size_off, self.size_cells)?;
+        self.off = size_off + self.size_cells as usize * 4;
+        Some(Region { base, size })
+    }
+}
+
+pub struct Ranges<'a> {
+    value: &'a [u8],
+    off: usize,
+    child_cells: u32,
+    parent_cells: u32,
+    size_cells: u32,
+}
+
+impl<'a> Iterator for Ranges<'a> {
+    type Item = (u64, u64, u64);
+
+    fn next(&mut self) -> Option<(u64, u64, u64)> {
+        let cells = [self.child_cells, self.parent_cells, self.size_cells];
+        if cells.iter().any(|&c| c > MAX_CELLS) {
+            return None;
+        }
+        if self.child_cells + self.parent_cells + self.size_cells == 0 {
+            return None;
+        }
+        let mut off = self.off;
+        let child = read_cells(self.value, off, self.child_cells)?;
+        off += self.child_cells as usize * 4;
+        let parent = read_cells(self.value, off, self.parent_cells)?;
+        off += self.parent_cells as usize * 4;
+        let size = read_cells(self.value, off, self.size_cells)?;
+        self.off = off + self.size_cells as usize * 4;
+        Some((child, parent, size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a blob. Tokens go to the structure block in the order they are added.
+    #[derive(Default)]
+    struct Blob {
+        structs: Vec<u8>,
+        strings: Vec<u8>,
+        reserved: Vec<(u64, u64)>,
+    }
+
+    impl Blob {
+        fn token(&mut self, t: u32) {
+            self.structs.extend_from_slice(&t.to_be_bytes());
+        }
+
+        fn pad(&mut self) {
+            let len = align4(self.structs.len());
+            self.structs.resize(len, 0);
+        }
+
+        fn begin(&mut self, name: &str) -> &mut Blob {
+            self.token(FDT_BEGIN_NODE);
+            self.structs.extend_from_slice(name.as_bytes());
+            self.structs.push(0);
+            self.pad();
+            self
+        }
+
+        fn end(&mut self) -> &mut Blob {
+            self.token(FDT_END_NODE);
+            self
+        }
+
+        fn prop(&mut self, name: &str, value: &[u8]) -> &mut Blob {
+            let name_off = self.strings.len() as u32;
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            self.token(FDT_PROP);
+            self.token(value.len() as u32);
+            self.token(name_off);
+            self.structs.extend_from_slice(value);
+            self.pad();
+            self
+        }
+
+        fn prop_str(&mut self, name: &str, value: &str) -> &mut Blob {
+            let mut v = value.as_bytes().to_vec();
+            v.push(0);
+            self.prop(name, &v)
+        }
+
+        fn prop_cells(&mut self, name: &str, cells: &[u32]) -> &mut Blob {
+            let v: Vec<u8> = cells
+                .iter()
+                .flat_map(|c| c.to_be_bytes().to_vec())
+                .collect();
+            self.prop(name, &v)
+        }
+
+        fn reserve(&mut self, base: u64, size: u64) -> &mut Blob {
+            self.reserved.push((base, size));
+            self
+        }
+
+        /// Header, reservation block, structure block, strings.
+        fn build(&self) -> Vec<u8> {
+            let rsvmap = HEADER_SIZE;
+            let structs = rsvmap + (self.reserved.len() + 1) * 16;
+            let size_struct = self.structs.len() + 4;
+            let strings = structs + size_struct;
+            let total = strings + self.strings.len();
+
+            let mut data = Vec::new();
+            let header = [
+                MAGIC,
+                total as u32,
+                structs as u32,
+                strings as u32,
+                rsvmap as u32,
+                17,
+                16,
+                0,
+                self.strings.len() as u32,
+                size_struct as u32,
+            ];
+            for w in header.iter() {
+                data.extend_from_slice(&w.to_be_bytes());
+            }
+            for &(base, size) in self.reserved.iter().chain(&[(0, 0)]) {
+                data.extend_from_slice(&base.to_be_bytes());
+                data.extend_from_slice(&size.to_be_bytes());
+            }
+            data.extend_from_slice(&self.structs);
+            data.extend_from_slice(&9u32.to_be_bytes()); // FDT_END
+            data.extend_from_slice(&self.strings);
+            data
+        }
+    }
+
+    /// A Raspberry Pi 3 like tree.
+    fn pi3() -> Vec<u8> {
+        let mut b = Blob::default();
+        b.reserve(0, 0x1000)
+            .begin("")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[1])
+            .begin("chosen")
+            .prop_str("bootargs", "heap=16M quiet")
+            .end()
+            .begin("memory@0")
+            .prop_str("device_type", "memory")
+            .prop_cells("reg", &[0, 0x3B40_0000, 0x4000_0000, 0x100_0000])
+            .end()
+            .begin("reserved-memory")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[1])
+            .begin("linux,cma")
+            .prop_cells("reg", &[0x3000_0000, 0x400_0000])
+            .end()
+            .end()
+            .begin("soc")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[1])
+            .prop_cells("ranges", &[0x7E00_0000, 0x3F00_0000, 0x100_0000])
+            .begin("gpio@7e200000")
+            .prop(
+                "compatible",
+                b"brcm,bcm2835-gpio\0brcm,bcm2835-armctrl-ic\0",
+            )
+            .end()
+            .begin("serial@7e201000")
+            .prop("compatible", b"arm,pl011\0arm,primecell\0")
+            .prop_cells("reg", &[0x7E20_1000, 0x200])
+            .end()
+            .end()
+            .end();
+        b.build()
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let mut data = pi3();
+        data[0] = 0;
+        assert!(matches!(Fdt::new(&data), Err(FdtError::BadMagic)));
+
+        let mut data = pi3();
+        data[23] = 15;
+        assert!(matches!(Fdt::new(&data), Err(FdtError::BadVersion)));
+
+        let data = pi3();
+        assert!(matches!(
+            Fdt::new(&data[..data.len() - 1]),
+            Err(FdtError::Truncated)
+        ));
+        assert!(matches!(Fdt::new(&data[..8]), Err(FdtError::Truncated)));
+    }
+
+    #[test]
+    fn finds_nodes() {
+        let data = pi3();
+        let fdt = Fdt::new(&data).unwrap();
+        assert_eq!(fdt.root().unwrap().name(), "");
+        assert_eq!(fdt.find("/memory").unwrap().name(), "memory@0");
+        assert_eq!(
+            fdt.find("/soc/serial@7e201000").unwrap().name(),
+            "serial@7e201000"
+        );
+        assert!(fdt.find("/soc/serial@7e202000").is_none());
+        assert!(fdt.find("/nothing").is_none());
+        assert_eq!(fdt.bootargs(), Some("heap=16M quiet"));
+
+        let serial = fdt.find_compatible("arm,primecell").unwrap();
+        assert_eq!(serial.name(), "serial@7e201000");
+        assert_eq!(
+            serial.compatible().collect::<Vec<_>>(),
+            ["arm,pl011", "arm,primecell"]
+        );
+        assert!(fdt.find_compatible("brcm,bcm2835-aux").is_none());
+    }
+
+    #[test]
+    fn reads_memory_and_reservations() {
+        let data = pi3();
+        let fdt = Fdt::new(&data).unwrap();
+        assert_eq!(
+            fdt.memory().collect::<Vec<_>>(),
+            [
+                Region {
+                    base: 0,
+                    size: 0x3B40_0000
+                },
+                Region {
+                    base: 0x4000_0000,
+                    size: 0x100_0000
+                },
+            ]
+        );
+        assert_eq!(
+            fdt.reservations().collect::<Vec<_>>(),
+            [
+                Region {
+                    base: 0,
+                    size: 0x1000
+                },
+                Region {
+                    base: 0x3000_0000,
+                    size: 0x400_0000
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn translates_soc_addresses() {
+        let data = pi3();
+        let fdt = Fdt::new(&data).unwrap();
+        assert_eq!(fdt.soc_to_cpu(0x7E00_0000), Some(0x3F00_0000));
+        assert_eq!(fdt.soc_to_cpu(0x7E20_1000), Some(0x3F20_1000));
+        assert_eq!(fdt.soc_to_cpu(0x7F00_0000), None);
+        assert_eq!(fdt.soc_to_cpu(0x7DFF_FFFF), None);
+    }
+
+    #[test]
+    fn default_cells_without_properties() {
+        let mut b = Blob::default();
+        b.begin("")
+            .begin("memory")
+            .prop_cells("reg", &[0x1, 0x0, 0x1000])
+            .end()
+            .end();
+        let data = b.build();
+        let fdt = Fdt::new(&data).unwrap();
+        assert_eq!(
+            fdt.memory().collect::<Vec<_>>(),
+            [Region {
+                base: 0x1_0000_0000,
+                size: 0x1000
+            }]
+        );
+    }
+
+    #[test]
+    fn huge_cells_end_reg_and_ranges() {
+        let mut b = Blob::default();
+        b.begin("")
+            .prop_cells("#address-cells", &[0xFFFF_FFFF])
+            .prop_cells("#size-cells", &[1])
+            .begin("memory")
+            .prop_cells("reg", &[0, 0x1000])
+            .end()
+            .begin("soc")
+            .prop_cells("#address-cells", &[0x8000_0000])
+            .prop_cells("#size-cells", &[0x8000_0000])
+            .prop_cells("ranges", &[0x7E00_0000, 0x3F00_0000, 0x100_0000])
+            .end()
+            .end();
+        let data = b.build();
+        let fdt = Fdt::new(&data).unwrap();
+        assert_eq!(fdt.memory().count(), 0);
+        assert_eq!(fdt.find("/soc").unwrap().ranges().count(), 0);
+        assert_eq!(fdt.soc_to_cpu(0x7E00_0000), None);
+    }
+
+    #[test]
+    fn short_values_end_reg() {
+        let mut b = Blob::default();
+        b.begin("")
+            .prop_cells("#address-cells", &[1])
+            .prop_cells("#size-cells", &[1])
+            .begin("memory")
+            .prop_cells("reg", &[0, 0x1000, 0x2000])
+            .end()
+            .end();
+        let data = b.build();
+        let fdt = Fdt::new(&data).unwrap();
+        assert_eq!(fdt.memory().count(), 1);
+    }
+}
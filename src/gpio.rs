@@ -93,7 +93,7 @@ register_bitfields! {
     ]
 }
 
-const GPIO_BASE: u32 = super::MMIO_BASE + 0x20_0000;
+const GPIO_BASE: u64 = super::MMIO_BASE + 0x20_0000;
 
 pub struct GPIO {}
 
@@ -0,0 +1,111 @@
+//! Counters of the global allocator, kept by `TrackingAlloc`.
+
+use crate::exception::ConsoleOut;
+use crate::sync::{self, Mutex};
+use core::alloc::{GlobalAlloc, Layout};
+
+#[derive(Copy, Clone)]
+pub struct HeapStats {
+    /// Successful allocations since boot.
+    pub count: u32,
+    /// Allocations not freed yet.
+    pub live: u32,
+    pub in_use: usize,
+    pub peak: usize,
+    pub failed: u32,
+}
+
+impl HeapStats {
+    const fn new() -> HeapStats {
+        HeapStats {
+            count: 0,
+            live: 0,
+            in_use: 0,
+            peak: 0,
+            failed: 0,
+        }
+    }
+}
+
+static STATS: Mutex<HeapStats> = Mutex::new(HeapStats::new());
+
+/// Counts what goes through `inner`.
+pub struct TrackingAlloc<A> {
+    pub inner: A,
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAlloc<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let p = self.inner.alloc(layout);
+        let mut s = STATS.lock();
+        if p.is_null() {
+            s.failed = s.failed.wrapping_add(1);
+        } else {
+            s.count = s.count.wrapping_add(1);
+            s.live += 1;
+            s.in_use += layout.size();
+            s.peak = core::cmp::max(s.peak, s.in_use);
+        }
+        p
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+        let mut s = STATS.lock();
+        s.live -= 1;
+        s.in_use -= layout.size();
+    }
+}
+
+impl<A: GlobalAlloc> TrackingAlloc<A> {
+    /// Largest block up to `limit` bytes which can be allocated now, found by allocating and
+    /// freeing blocks of halving sizes. Not counted in the stats.
+    pub fn largest_free(&self, limit: usize) -> usize {
+        const ALIGN: usize = 8;
+        let fits = |size: usize| unsafe {
+            let layout = Layout::from_size_align_unchecked(size, ALIGN);
+            let p = self.inner.alloc(layout);
+            if !p.is_null() {
+                self.inner.dealloc(p, layout);
+            }
+            !p.is_null()
+        };
+
+        // IRQ handlers must not allocate in between. Other cores can, so it is a hint.
+        sync::critical_section(|| {
+            let (mut lo, mut hi) = (0, limit / ALIGN);
+            while lo < hi {
+                let mid = hi - (hi - lo) / 2;
+                if fits(mid * ALIGN) {
+                    lo = mid;
+                } else {
+                    hi = mid - 1;
+                }
+            }
+            lo * ALIGN
+        })
+    }
+}
+
+pub fn get() -> HeapStats {
+    *STATS.lock()
+}
+
+/// Print the counters. All values are hex, sizes in bytes.
+pub fn dump(out: &dyn ConsoleOut, largest_free: usize) {
+    let s = get();
+    out.puts("[Heap stats] count    live     in use   peak     largest  failed\n");
+    out.puts("             ");
+    for v in &[
+        s.count,
+        s.live,
+        s.in_use as u32,
+        s.peak as u32,
+        largest_free as u32,
+        s.failed,
+    ] {
+        out.hex(*v);
+        out.puts(" ");
+    }
+    out.puts("\n");
+}
@@ -1,22 +1,204 @@
+use crate::local_intc::{self, LocalIntc, LocalInterruptId};
 use register::{
     mmio::{ReadOnly, ReadWrite},
     register_bitfields,
 };
 
-const INTC_BASE: u32 = super::MMIO_BASE + 0xB200;
+const INTC_BASE: u64 = super::MMIO_BASE + 0xB200;
 
-pub struct InterruptId {}
+/// GPU side interrupts, routed through IRQ_PENDING[0..1].
+#[allow(dead_code)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u32)]
+pub enum InterruptId {
+    SystemTimer0 = 0, // used by GPU
+    SystemTimer1 = 1,
+    SystemTimer2 = 2, // used by GPU
+    SystemTimer3 = 3,
+    Codec0 = 4,
+    Codec1 = 5,
+    Codec2 = 6,
+    Jpeg = 7,
+    Isp = 8,
+    Usb = 9,
+    V3d = 10,
+    Transposer = 11,
+    MulticoreSync0 = 12,
+    MulticoreSync1 = 13,
+    MulticoreSync2 = 14,
+    MulticoreSync3 = 15,
+    Dma0 = 16,
+    Dma1 = 17,
+    Dma2 = 18,
+    Dma3 = 19,
+    Dma4 = 20,
+    Dma5 = 21,
+    Dma6 = 22,
+    Dma7 = 23,
+    Dma8 = 24,
+    Dma9 = 25,
+    Dma10 = 26,
+    /// Shared by DMA ch 11-14.
+    Dma11To14 = 27,
+    /// Shared by all DMA channels.
+    DmaAll = 28,
+    Aux = 29,
+    Arm = 30,
+    VpuDma = 31,
+    HostPort = 32,
+    VideoScaler = 33,
+    Ccp2tx = 34,
+    Sdc = 35,
+    Dsi0 = 36,
+    Ave = 37,
+    Cam0 = 38,
+    Cam1 = 39,
+    Hdmi0 = 40,
+    Hdmi1 = 41,
+    PixelValve1 = 42,
+    I2cSpiSlave = 43,
+    Dsi1 = 44,
+    PixelValve0 = 45,
+    PixelValve2 = 46,
+    Cpr = 47,
+    Smi = 48,
+    Gpio0 = 49,
+    Gpio1 = 50,
+    Gpio2 = 51,
+    Gpio3 = 52,
+    I2c = 53,
+    Spi = 54,
+    Pcm = 55,
+    SdHost = 56,
+    Uart = 57,
+    SlimBus = 58,
+    Vec = 59,
+    Cpg = 60,
+    Rng = 61,
+    Emmc = 62,
+    EthPcie = 63,
+}
+
+#[allow(dead_code)]
 impl InterruptId {
-    pub const DMA: u32 = 16;
-    pub const TIMER1: u32 = 1;
-    pub const TIMER3: u32 = 1;
+    pub const COUNT: u32 = 64;
+
+    /// Convert a bit number of IRQ_PENDING into its id.
+    pub fn from_u32(id: u32) -> Option<InterruptId> {
+        if id < Self::COUNT {
+            // all values in 0..64 are assigned above.
+            Some(unsafe { core::mem::transmute::<u32, InterruptId>(id) })
+        } else {
+            None
+        }
+    }
+
+    pub fn dma(ch: usize) -> Option<InterruptId> {
+        match ch {
+            0..=10 => Self::from_u32(InterruptId::Dma0 as u32 + ch as u32),
+            11..=14 => Some(InterruptId::Dma11To14),
+            _ => None,
+        }
+    }
+}
+
+/// ARM side interrupts, routed through BASIC_PENDING bit 0-7.
+#[allow(dead_code)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u32)]
+pub enum BasicInterruptId {
+    ArmTimer = 0,
+    ArmMailbox = 1,
+    ArmDoorbell0 = 2,
+    ArmDoorbell1 = 3,
+    Gpu0Halted = 4,
+    Gpu1Halted = 5,
+    IllegalAccessType1 = 6,
+    IllegalAccessType0 = 7,
 }
 
-pub struct BasicInterruptId {}
+#[allow(dead_code)]
 impl BasicInterruptId {
-    pub const ARM_TIMER: u32 = 0;
+    pub const COUNT: u32 = 8;
+
+    pub fn from_u32(id: u32) -> Option<BasicInterruptId> {
+        if id < Self::COUNT {
+            Some(unsafe { core::mem::transmute::<u32, BasicInterruptId>(id) })
+        } else {
+            None
+        }
+    }
 }
 
+/// Anything which can be delivered to `InterruptionSource::on_interruption`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum IrqSource {
+    Gpu(InterruptId),
+    Basic(BasicInterruptId),
+    /// Per core source of the local controller, on the core taking the interrupt.
+    Local(LocalInterruptId),
+}
+
+/// Set of GPU and basic interrupt lines.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct LineMask {
+    pub gpu: u64,
+    /// bit 0-7
+    pub basic: u32,
+}
+
+#[allow(dead_code)]
+impl LineMask {
+    pub const EMPTY: LineMask = LineMask { gpu: 0, basic: 0 };
+
+    pub fn of(source: IrqSource) -> LineMask {
+        match source {
+            IrqSource::Gpu(id) => LineMask {
+                gpu: 1 << id as u32,
+                basic: 0,
+            },
+            IrqSource::Basic(id) => LineMask {
+                gpu: 0,
+                basic: 1 << id as u32,
+            },
+            // not maskable at this controller.
+            IrqSource::Local(_) => LineMask::EMPTY,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.gpu == 0 && self.basic == 0
+    }
+
+    pub fn contains(&self, source: IrqSource) -> bool {
+        !self.and(&LineMask::of(source)).is_empty()
+    }
+
+    pub fn or(&self, other: &LineMask) -> LineMask {
+        LineMask {
+            gpu: self.gpu | other.gpu,
+            basic: self.basic | other.basic,
+        }
+    }
+
+    pub fn and(&self, other: &LineMask) -> LineMask {
+        LineMask {
+            gpu: self.gpu & other.gpu,
+            basic: self.basic & other.basic,
+        }
+    }
+
+    pub fn and_not(&self, other: &LineMask) -> LineMask {
+        LineMask {
+            gpu: self.gpu & !other.gpu,
+            basic: self.basic & !other.basic,
+        }
+    }
+}
+
+/// Lines enabled through `Interrupt`. Enable registers cannot be read back.
+static mut ENABLED: LineMask = LineMask::EMPTY;
+
 pub struct Interrupt {}
 
 #[allow(non_snake_case)]
@@ -25,7 +207,7 @@ pub struct RegisterBlock {
     BASIC_PENDING: ReadOnly<u32, BASIC_PENDING::Register>,
     /// Holds interrupts from GPU side. INT no. 0-63.
     IRQ_PENDING: [ReadWrite<u32>; 2],
-    FIQ_CONTROL: ReadWrite<u32>,
+    FIQ_CONTROL: ReadWrite<u32, FIQ_CONTROL::Register>,
     /// Write 1 to enable
     ENABLE_IRQ: [ReadWrite<u32>; 2],
     ENABLE_BASIC_IRQ: ReadWrite<u32, ENABLE_BASIC::Register>,
@@ -64,6 +246,14 @@ register_bitfields! {
         ARM_MAILBOX_IRQ_PENDING OFFSET(1) NUMBITS(1)[],
         ARM_TIMER_IRQ_PENDING OFFSET(0) NUMBITS(1)[]
     ],
+    FIQ_CONTROL[
+        ENABLE OFFSET(7) NUMBITS(1)[
+            Enabled = 1,
+            Disabled = 0
+        ],
+        /// 0-63: GPU interrupts, 64-71: ARM (basic) interrupts.
+        SOURCE OFFSET(0) NUMBITS(7)[]
+    ],
     ENABLE_BASIC[
         UNUSED OFFSET(8) NUMBITS(24)[]
         // bit 0-7: enable bits for irqs like illegal access, etc.
@@ -94,54 +284,113 @@ impl Interrupt {
         return ((h as u64) << (32 as u64)) + l as u64;
     }
 
-    pub fn enable_irq(&self, id: u32) {
+    pub fn enable_irq(&self, id: InterruptId) {
+        unsafe { ENABLED.gpu |= 1 << id as u32 };
+        let id = id as u32;
         if id < 32 {
             self.ENABLE_IRQ[0].set(1 << id);
-        } else if id < 64 {
-            self.ENABLE_IRQ[1].set(1 << (id - 32));
         } else {
-            return;
+            self.ENABLE_IRQ[1].set(1 << (id - 32));
         }
     }
 
-    pub fn disable_irq(&self, id: u32) {
+    pub fn disable_irq(&self, id: InterruptId) {
+        unsafe { ENABLED.gpu &= !(1 << id as u32) };
+        let id = id as u32;
         if id < 32 {
             self.DISABLE_IRQ[0].set(1 << id);
-        } else if id < 64 {
-            self.DISABLE_IRQ[1].set(1 << (id - 32));
         } else {
-            return;
+            self.DISABLE_IRQ[1].set(1 << (id - 32));
         }
     }
 
-    pub fn is_irq_enabled(&self, id: u32) -> bool {
+    pub fn is_irq_pending(&self, id: InterruptId) -> bool {
+        let id = id as u32;
         if id < 32 {
             (self.IRQ_PENDING[0].get() & (1 << id)) != 0
-        } else if id < 64 {
-            (self.IRQ_PENDING[1].get() & (1 << (id - 32))) != 0
         } else {
-            return false;
+            (self.IRQ_PENDING[1].get() & (1 << (id - 32))) != 0
         }
     }
 
-    pub fn enable_basic_irq(&self, id: u32) {
-        if id < 8 {
-            self.ENABLE_BASIC_IRQ.set(1 << id);
+    pub fn enable_basic_irq(&self, id: BasicInterruptId) {
+        unsafe { ENABLED.basic |= 1 << id as u32 };
+        self.ENABLE_BASIC_IRQ.set(1 << id as u32);
+    }
+
+    pub fn disable_basic_irq(&self, id: BasicInterruptId) {
+        unsafe { ENABLED.basic &= !(1 << id as u32) };
+        self.DISABLE_BASIC_IRQ.set(1 << id as u32);
+    }
+
+    pub fn is_basic_irq_pending(&self, id: BasicInterruptId) -> bool {
+        (self.BASIC_PENDING.get() & (1 << id as u32)) != 0
+    }
+
+    pub fn enable(&self, source: IrqSource) {
+        match source {
+            IrqSource::Gpu(id) => self.enable_irq(id),
+            IrqSource::Basic(id) => self.enable_basic_irq(id),
+            IrqSource::Local(id) => LocalIntc::new().enable(local_intc::core_id(), id),
         }
     }
 
-    pub fn disable_basic_irq(&self, id: u32) {
-        if id < 8 {
-            self.DISABLE_BASIC_IRQ.set(1 << id);
+    pub fn disable(&self, source: IrqSource) {
+        match source {
+            IrqSource::Gpu(id) => self.disable_irq(id),
+            IrqSource::Basic(id) => self.disable_basic_irq(id),
+            IrqSource::Local(id) => LocalIntc::new().disable(local_intc::core_id(), id),
         }
     }
 
-    pub fn is_basic_irq_pending(&self, id: u32) -> bool {
-        if id < 8 {
-            return (self.BASIC_PENDING.get() & (1 << id)) != 0;
+    pub fn get_enabled_lines(&self) -> LineMask {
+        unsafe { ENABLED }
+    }
+
+    /// Pending lines, regardless of being masked or not.
+    pub fn get_pending_lines(&self) -> LineMask {
+        LineMask {
+            gpu: self.get_raw_pending(),
+            basic: self.get_raw_basic_pending() & ((1 << BasicInterruptId::COUNT) - 1),
         }
+    }
+
+    /// Temporarily block lines at the controller. They stay enabled logically.
+    pub fn mask_lines(&self, mask: &LineMask) {
+        self.DISABLE_IRQ[0].set(mask.gpu as u32);
+        self.DISABLE_IRQ[1].set((mask.gpu >> 32) as u32);
+        self.DISABLE_BASIC_IRQ.set(mask.basic);
+    }
+
+    /// Undo `mask_lines`. Lines disabled in the meantime are left disabled.
+    pub fn unmask_lines(&self, mask: &LineMask) {
+        let mask = mask.and(&self.get_enabled_lines());
+        self.ENABLE_IRQ[0].set(mask.gpu as u32);
+        self.ENABLE_IRQ[1].set((mask.gpu >> 32) as u32);
+        self.ENABLE_BASIC_IRQ.set(mask.basic);
+    }
+
+    /// Deliver `source` as FIQ instead of IRQ. Only one source can be selected.
+    pub fn route_to_fiq(&self, source: IrqSource) {
+        // a source selected for FIQ must not be enabled as IRQ.
+        self.disable(source);
+        let no = match source {
+            IrqSource::Gpu(id) => id as u32,
+            IrqSource::Basic(id) => InterruptId::COUNT + id as u32,
+            IrqSource::Local(id) => {
+                return LocalIntc::new().route_to_fiq(local_intc::core_id(), id);
+            }
+        };
+        self.FIQ_CONTROL
+            .write(FIQ_CONTROL::SOURCE.val(no) + FIQ_CONTROL::ENABLE::Enabled);
+    }
+
+    pub fn disable_fiq(&self) {
+        self.FIQ_CONTROL.write(FIQ_CONTROL::ENABLE::Disabled);
+    }
 
-        false
+    pub fn is_fiq_enabled(&self) -> bool {
+        self.FIQ_CONTROL.is_set(FIQ_CONTROL::ENABLE)
     }
 
     pub fn is_any_irq_pending(&self) -> bool {
@@ -0,0 +1,110 @@
+//! Inter-processor interrupts over mailbox 0 of the local controller.
+
+use crate::exception::InterruptionSource;
+use crate::interrupt::IrqSource;
+use crate::local_intc::{self, LocalIntc, LocalInterruptId, CORES};
+use crate::sync;
+use crate::thread;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const IPI_MAILBOX: usize = 0;
+
+/// Each message is one bit of the mailbox, so different messages never overwrite each other.
+#[allow(dead_code)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+#[repr(u32)]
+pub enum IpiMessage {
+    /// Ask the target to run its scheduler.
+    Reschedule = 0,
+    /// Run the function queued by `call_on`.
+    CallFunction = 1,
+    /// Park the target with interrupts masked.
+    Stop = 2,
+}
+
+/// Function queued for each core by `call_on`. 0 when empty.
+static CALL: [AtomicUsize; CORES] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+pub struct Ipi {}
+
+impl Ipi {
+    pub fn new() -> Ipi {
+        Ipi {}
+    }
+}
+
+impl InterruptionSource for Ipi {
+    fn on_interruption(&self, _source: IrqSource) {
+        let core = local_intc::core_id();
+        let intc = LocalIntc::new();
+        let pending = intc.read_mailbox(core, IPI_MAILBOX);
+        intc.clear_mailbox(core, IPI_MAILBOX, pending);
+
+        if pending & (1 << IpiMessage::Reschedule as u32) != 0 {
+            thread::request_reschedule();
+        }
+        if pending & (1 << IpiMessage::CallFunction as u32) != 0 {
+            let f = CALL[core].load(Ordering::Acquire);
+            if f != 0 {
+                let f: fn() = unsafe { core::mem::transmute(f) };
+                f();
+                // tell the caller we are done.
+                CALL[core].store(0, Ordering::Release);
+            }
+        }
+        if pending & (1 << IpiMessage::Stop as u32) != 0 {
+            stop_this_core();
+        }
+    }
+}
+
+/// The source to register `Ipi` with.
+pub const SOURCE: IrqSource = IrqSource::Local(LocalInterruptId::Mailbox0);
+
+/// Accept IPIs on the calling core. Every core has to call this once.
+pub fn enable_on_this_core() {
+    LocalIntc::new().enable(local_intc::core_id(), LocalInterruptId::Mailbox0);
+}
+
+pub fn send(core: usize, message: IpiMessage) {
+    // make our writes visible before the target wakes up.
+    unsafe { asm!("dsb sy" :::: "volatile") };
+    LocalIntc::new().write_mailbox(core, IPI_MAILBOX, 1 << message as u32);
+}
+
+/// Run `f` on `core` from its IRQ context, and wait until it returns.
+/// Returns false if the target already has another call queued.
+///
+/// The caller must have IRQ unmasked: there is no timeout, and two cores calling each other
+/// with IRQ masked would wait forever.
+pub fn call_on(core: usize, f: fn()) -> bool {
+    assert!(!raspi3_boot::irq_masked(), "call_on with IRQ masked");
+    if core >= CORES {
+        return false;
+    }
+    if core == local_intc::core_id() {
+        f();
+        return true;
+    }
+
+    if !sync::compare_and_set_usize(&CALL[core], 0, f as usize) {
+        return false;
+    }
+    send(core, IpiMessage::CallFunction);
+    while CALL[core].load(Ordering::Acquire) != 0 {
+        unsafe { asm!("nop" :::: "volatile") };
+    }
+    true
+}
+
+fn stop_this_core() -> ! {
+    unsafe { raspi3_boot::disable_irq() };
+    loop {
+        unsafe { raspi3_boot::wfe() };
+    }
+}
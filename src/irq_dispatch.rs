@@ -0,0 +1,293 @@
+//! Table from interrupt number to the handlers registered for it.
+
+use crate::exception::InterruptionSource;
+use crate::interrupt::{BasicInterruptId, Interrupt, InterruptId, IrqSource, LineMask};
+use crate::irq_stats;
+use crate::local_intc::{self, LocalIntc, LocalInterruptId};
+use crate::sync::critical_section;
+use crate::timer::TIMER;
+
+/// Number of handlers which can share one interrupt line.
+const MAX_SHARED: usize = 4;
+
+const GPU_LINES: usize = InterruptId::COUNT as usize;
+const BASIC_LINES: usize = BasicInterruptId::COUNT as usize;
+const LOCAL_LINES: usize = LocalInterruptId::COUNT as usize;
+pub const LINES: usize = GPU_LINES + BASIC_LINES + LOCAL_LINES;
+
+#[derive(Copy, Clone)]
+struct Entry {
+    device: &'static dyn InterruptionSource,
+    enabled: bool,
+}
+
+/// Identifies one registration. Returned by `register`.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct IrqHandle {
+    line: usize,
+    slot: usize,
+}
+
+pub enum DispatchError {
+    /// All slots for the line are in use.
+    NoSpace,
+    NotRegistered,
+}
+pub type Result<T> = ::core::result::Result<T, DispatchError>;
+
+static mut TABLE: [[Option<Entry>; MAX_SHARED]; LINES] = [[None; MAX_SHARED]; LINES];
+
+/// Smaller value is served first and can preempt larger ones.
+pub type Priority = u8;
+pub const DEFAULT_PRIORITY: Priority = 0x80;
+
+static mut PRIORITY: [Priority; LINES] = [DEFAULT_PRIORITY; LINES];
+
+/// Lines masked at the controller by handlers currently running.
+static mut MASKED: LineMask = LineMask::EMPTY;
+
+/// The only source delivered as FIQ, with its handler.
+static mut FIQ: Option<(IrqSource, &'static dyn InterruptionSource)> = None;
+
+pub fn line(source: IrqSource) -> usize {
+    match source {
+        IrqSource::Gpu(id) => id as usize,
+        IrqSource::Basic(id) => GPU_LINES + id as usize,
+        IrqSource::Local(id) => GPU_LINES + BASIC_LINES + id as usize,
+    }
+}
+
+pub fn source(line: usize) -> IrqSource {
+    if line < GPU_LINES {
+        IrqSource::Gpu(InterruptId::from_u32(line as u32).unwrap())
+    } else if line < GPU_LINES + BASIC_LINES {
+        IrqSource::Basic(BasicInterruptId::from_u32((line - GPU_LINES) as u32).unwrap())
+    } else {
+        let id = (line - GPU_LINES - BASIC_LINES) as u32;
+        IrqSource::Local(LocalInterruptId::from_u32(id).unwrap())
+    }
+}
+
+fn entry(handle: IrqHandle) -> Result<&'static mut Entry> {
+    match unsafe { TABLE[handle.line][handle.slot].as_mut() } {
+        Some(e) => Ok(e),
+        None => Err(DispatchError::NotRegistered),
+    }
+}
+
+/// Add `device` to the handlers of `source`. Handler starts enabled.
+///
+/// # Safety
+///
+/// - Must not race with `dispatch`: call with IRQ masked or from IRQ context.
+pub unsafe fn register(
+    source: IrqSource,
+    device: &'static dyn InterruptionSource,
+) -> Result<IrqHandle> {
+    let line = line(source);
+    for (slot, e) in TABLE[line].iter_mut().enumerate() {
+        if e.is_none() {
+            *e = Some(Entry {
+                device,
+                enabled: true,
+            });
+            return Ok(IrqHandle { line, slot });
+        }
+    }
+    Err(DispatchError::NoSpace)
+}
+
+/// Remove a handler. The line is masked at the controller once nobody listens to it.
+///
+/// # Safety
+///
+/// - Same as `register`.
+pub unsafe fn unregister(handle: IrqHandle) -> Result<()> {
+    entry(handle)?;
+    TABLE[handle.line][handle.slot] = None;
+
+    if TABLE[handle.line].iter().all(|e| e.is_none()) {
+        Interrupt::new().disable(source(handle.line));
+    }
+    Ok(())
+}
+
+/// Resume calling a handler disabled by `disable`.
+pub fn enable(handle: IrqHandle) -> Result<()> {
+    // not while `dispatch` looks at the entry.
+    critical_section(|| {
+        entry(handle)?.enabled = true;
+        Ok(())
+    })
+}
+
+/// Skip a handler without removing it. Others on the same line are still called.
+pub fn disable(handle: IrqHandle) -> Result<()> {
+    critical_section(|| {
+        entry(handle)?.enabled = false;
+        Ok(())
+    })
+}
+
+/// Serve the local sources of this core, from the root of the interrupt tree.
+/// Legacy controller is served by `handle_pending` when `LocalInterruptId::Gpu` is set.
+/// Returns how many lines were served.
+///
+/// Local sources are per core and short, so they are served with IRQ masked.
+///
+/// # Safety
+///
+/// - Call from the IRQ vector only, with IRQ masked at the CPU.
+pub unsafe fn handle_local_pending() -> usize {
+    let mut pend = LocalIntc::new().get_irq_source(local_intc::core_id());
+    let mut served = 0;
+
+    if pend & (1 << LocalInterruptId::Gpu as u32) != 0 {
+        pend &= !(1 << LocalInterruptId::Gpu as u32);
+        served += handle_pending();
+    }
+
+    pend &= (1 << LocalInterruptId::COUNT) - 1;
+    while pend != 0 {
+        let id = pend.trailing_zeros();
+        pend &= pend - 1;
+
+        let source = IrqSource::Local(LocalInterruptId::from_u32(id).unwrap());
+        let start = TIMER::now();
+        if dispatch(source) == 0 {
+            Interrupt::new().disable(source);
+        }
+        irq_stats::record(source, TIMER::now().wrapping_sub(start));
+        served += 1;
+    }
+    served
+}
+
+/// Handlers of lines with higher priority can preempt those with lower one.
+///
+/// # Safety
+///
+/// - Must not race with `handle_pending`: call with IRQ masked.
+pub unsafe fn set_priority(source: IrqSource, prio: Priority) {
+    PRIORITY[line(source)] = prio;
+}
+
+pub fn get_priority(source: IrqSource) -> Priority {
+    unsafe { PRIORITY[line(source)] }
+}
+
+/// Call every enabled handler of `source`. Returns how many were called.
+pub fn dispatch(source: IrqSource) -> usize {
+    let mut called = 0;
+    for e in unsafe { TABLE[line(source)].iter() } {
+        if let Some(e) = e {
+            if e.enabled {
+                e.device.on_interruption(source);
+                called += 1;
+            }
+        }
+    }
+    called
+}
+
+/// Find the pending line to serve next: highest priority, lowest number among equals.
+fn highest_pending() -> Option<IrqSource> {
+    let int = Interrupt::new();
+    let pend = int
+        .get_pending_lines()
+        .and(&int.get_enabled_lines())
+        .and_not(unsafe { &MASKED });
+
+    let mut best: Option<usize> = None;
+    let mut consider = |line: usize| {
+        if best.map_or(true, |b| unsafe { PRIORITY[line] < PRIORITY[b] }) {
+            best = Some(line);
+        }
+    };
+
+    let mut gpu = pend.gpu;
+    while gpu != 0 {
+        consider(gpu.trailing_zeros() as usize);
+        gpu &= gpu - 1;
+    }
+    let mut basic = pend.basic;
+    while basic != 0 {
+        consider(GPU_LINES + basic.trailing_zeros() as usize);
+        basic &= basic - 1;
+    }
+
+    best.map(source)
+}
+
+/// Lines whose priority is equal to or lower than `prio`.
+fn lines_at_or_below(prio: Priority) -> LineMask {
+    let mut mask = LineMask::EMPTY;
+    for l in 0..LINES {
+        if unsafe { PRIORITY[l] } >= prio {
+            mask = mask.or(&LineMask::of(source(l)));
+        }
+    }
+    mask
+}
+
+/// Serve pending lines in priority order. Returns how many lines were served.
+///
+/// Each handler runs with IRQ unmasked at the CPU while lines of lower or equal
+/// priority are masked at the controller, so only higher priority can preempt it.
+///
+/// # Safety
+///
+/// - Call from the IRQ vector only, with IRQ masked at the CPU.
+pub unsafe fn handle_pending() -> usize {
+    let int = Interrupt::new();
+    let mut served = 0;
+
+    while let Some(source) = highest_pending() {
+        let outer = MASKED;
+        let masked = outer.or(&lines_at_or_below(PRIORITY[line(source)]));
+        let newly_masked = masked.and_not(&outer);
+        int.mask_lines(&newly_masked);
+        MASKED = masked;
+
+        let start = TIMER::now();
+        raspi3_boot::enable_irq();
+        let called = dispatch(source);
+        raspi3_boot::disable_irq();
+        irq_stats::record(source, TIMER::now().wrapping_sub(start));
+
+        MASKED = outer;
+        int.unmask_lines(&newly_masked);
+
+        if called == 0 {
+            // nobody can clear it. disable to avoid an interrupt storm.
+            int.disable(source);
+        }
+        served += 1;
+    }
+    served
+}
+
+/// Deliver `source` as FIQ to `device`, replacing any previous FIQ source.
+/// FIQ must also be unmasked on the CPU with `raspi3_boot::enable_fiq`.
+///
+/// # Safety
+///
+/// - Call with FIQ masked.
+pub unsafe fn register_fiq(source: IrqSource, device: &'static dyn InterruptionSource) {
+    FIQ = Some((source, device));
+    // local sources are routed on the calling core.
+    Interrupt::new().route_to_fiq(source);
+}
+
+/// Call the FIQ handler. Returns false if nothing is registered.
+pub fn dispatch_fiq() -> bool {
+    match unsafe { FIQ } {
+        Some((source, device)) => {
+            let start = TIMER::now();
+            device.on_interruption(source);
+            irq_stats::record(source, TIMER::now().wrapping_sub(start));
+            true
+        }
+        None => false,
+    }
+}
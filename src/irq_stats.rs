@@ -0,0 +1,113 @@
+//! Per-line interrupt counters and handler durations, kept for each core.
+
+use crate::exception::ConsoleOut;
+use crate::interrupt::IrqSource;
+use crate::irq_dispatch::{self, LINES};
+use crate::local_intc::{core_id, CORES};
+use crate::sync::critical_section;
+
+#[derive(Copy, Clone)]
+struct LineStats {
+    count: u32,
+    /// Durations in us, measured with the system timer.
+    total: u64,
+    min: u32,
+    max: u32,
+}
+
+impl LineStats {
+    const fn new() -> LineStats {
+        LineStats {
+            count: 0,
+            total: 0,
+            min: core::u32::MAX,
+            max: 0,
+        }
+    }
+}
+
+/// Each core counts what it serves, so counters are only written by their own core.
+static mut STATS: [[LineStats; LINES]; CORES] = [[LineStats::new(); LINES]; CORES];
+
+/// IRQ entries which found no pending line.
+static mut SPURIOUS: [u32; CORES] = [0; CORES];
+
+/// Account one served interrupt on the calling core. Durations include time spent in preempting
+/// handlers.
+pub fn record(source: IrqSource, duration_us: u32) {
+    // a nested handler on this core would record in the middle.
+    critical_section(|| {
+        let s = unsafe { &mut STATS[core_id()][irq_dispatch::line(source)] };
+        s.count = s.count.wrapping_add(1);
+        s.total += duration_us as u64;
+        s.min = core::cmp::min(s.min, duration_us);
+        s.max = core::cmp::max(s.max, duration_us);
+    });
+}
+
+pub fn record_spurious() {
+    critical_section(|| unsafe {
+        let n = &mut SPURIOUS[core_id()];
+        *n = n.wrapping_add(1);
+    });
+}
+
+/// Summed over the cores.
+fn line_stats(line: usize) -> LineStats {
+    let mut sum = LineStats::new();
+    for core in 0..CORES {
+        let s = unsafe { STATS[core][line] };
+        sum.count = sum.count.wrapping_add(s.count);
+        sum.total += s.total;
+        sum.min = core::cmp::min(sum.min, s.min);
+        sum.max = core::cmp::max(sum.max, s.max);
+    }
+    sum
+}
+
+#[allow(dead_code)]
+pub fn count(source: IrqSource) -> u32 {
+    line_stats(irq_dispatch::line(source)).count
+}
+
+pub fn spurious() -> u32 {
+    (0..CORES).fold(0, |n, core| n.wrapping_add(unsafe { SPURIOUS[core] }))
+}
+
+/// Clear the counters of the calling core. Other cores own theirs.
+#[allow(dead_code)]
+pub fn reset() {
+    critical_section(|| unsafe {
+        STATS[core_id()] = [LineStats::new(); LINES];
+        SPURIOUS[core_id()] = 0;
+    });
+}
+
+/// Print lines which fired at least once. All values are hex, durations in us.
+pub fn dump(out: &dyn ConsoleOut) {
+    out.puts("[IRQ stats] line     count    min      max      avg\n");
+    for l in 0..LINES {
+        let s = line_stats(l);
+        if s.count == 0 {
+            continue;
+        }
+        match irq_dispatch::source(l) {
+            IrqSource::Gpu(_) => out.puts("  gpu   "),
+            IrqSource::Basic(_) => out.puts("  basic "),
+            IrqSource::Local(_) => out.puts("  local "),
+        }
+        out.hex(l as u32);
+        out.puts(" ");
+        out.hex(s.count);
+        out.puts(" ");
+        out.hex(s.min);
+        out.puts(" ");
+        out.hex(s.max);
+        out.puts(" ");
+        out.hex((s.total / s.count as u64) as u32);
+        out.puts("\n");
+    }
+    out.puts("  spurious ");
+    out.hex(spurious());
+    out.puts("\n");
+}
@@ -0,0 +1,73 @@
+//! Runs ELF programs at EL0, each in its own address space.
+
+use crate::elf::{Elf, ElfError, Result, PF_W, PF_X};
+use crate::mmu::{self, AddressSpace};
+use crate::thread::{self, Priority, ThreadId};
+use alloc::boxed::Box;
+
+/// Programs must be linked to run here. The stack is above.
+pub const USER_LOAD_BASE: u64 = mmu::USER_VA_BASE;
+pub const USER_LOAD_SIZE: u64 = mmu::USER_CODE_END - mmu::USER_VA_BASE;
+
+/// Map the PT_LOAD segments of `elf` into `space` with their permissions, copy them and leave
+/// their BSS zeroed. Returns the entry point.
+pub fn load_into(elf: &Elf, space: &mut AddressSpace) -> Result<u64> {
+    elf.check_layout(USER_LOAD_BASE, USER_LOAD_SIZE)?;
+    for (ph, bytes) in elf.segments() {
+        if ph.memsz == 0 {
+            continue;
+        }
+        let mut prot = mmu::PROT_READ;
+        if ph.flags & PF_W != 0 {
+            prot |= mmu::PROT_WRITE;
+        }
+        if ph.flags & PF_X != 0 {
+            prot |= mmu::PROT_EXEC;
+        }
+        map_segment(space, ph.vaddr, ph.memsz, prot)?;
+        if space.copy_to(ph.vaddr, bytes).is_err() {
+            return Err(ElfError::OutOfMemory);
+        }
+    }
+
+    // the copied code must be fetched from memory, not from stale cache lines.
+    mmu::invalidate_icache();
+    Ok(elf.entry())
+}
+
+/// Map new zeroed pages for [va, va + len). Pages shared with a previous segment keep
+/// their frame and get the union of both permissions, which `check_layout` made sure is never
+/// writable and executable.
+fn map_segment(space: &mut AddressSpace, va: u64, len: u64, prot: mmu::Prot) -> Result<()> {
+    let mut page = va & !(mmu::PAGE_SIZE - 1);
+    while page < va + len {
+        let r = match space.translate(page) {
+            Some((_, old)) => space.protect(page, mmu::PAGE_SIZE, old | prot),
+            None => space.map_new(page, mmu::PAGE_SIZE, prot),
+        };
+        if r.is_err() {
+            return Err(ElfError::OutOfMemory);
+        }
+        page += mmu::PAGE_SIZE;
+    }
+    Ok(())
+}
+
+/// Load `image` into a new address space and start it as an EL0 thread.
+pub fn exec(
+    name: &'static str,
+    image: &[u8],
+    user_stack_size: usize,
+    priority: Priority,
+) -> Result<ThreadId> {
+    let elf = Elf::parse(image)?;
+    let mut space = match AddressSpace::new() {
+        Ok(s) => Box::new(s),
+        Err(_) => return Err(ElfError::OutOfMemory),
+    };
+    let entry = load_into(&elf, &mut space)?;
+    match thread::spawn_in(name, space, entry, 0, user_stack_size, priority) {
+        Ok(id) => Ok(id),
+        Err(_) => Err(ElfError::SpawnFailed),
+    }
+}
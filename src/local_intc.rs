@@ -0,0 +1,234 @@
+//! BCM2836 local peripherals: per-core interrupt sources, core timers and mailboxes.
+
+use register::{
+    mmio::{ReadOnly, ReadWrite, WriteOnly},
+    register_bitfields,
+};
+
+const LOCAL_INTC_BASE: u64 = crate::mmu::KERNEL_VA_BASE + 0x4000_0000;
+
+pub const CORES: usize = 4;
+
+/// Bit number of CORE_IRQ_SOURCE / CORE_FIQ_SOURCE.
+#[allow(dead_code)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u32)]
+pub enum LocalInterruptId {
+    /// Physical timer, secure.
+    CntPs = 0,
+    /// Physical timer, non-secure.
+    CntPns = 1,
+    /// Hypervisor timer.
+    CntHp = 2,
+    /// Virtual timer.
+    CntV = 3,
+    Mailbox0 = 4,
+    Mailbox1 = 5,
+    Mailbox2 = 6,
+    Mailbox3 = 7,
+    /// Everything from the legacy controller at MMIO_BASE + 0xB200.
+    Gpu = 8,
+    Pmu = 9,
+    /// Only for core 0.
+    AxiOutstanding = 10,
+    LocalTimer = 11,
+}
+
+#[allow(dead_code)]
+impl LocalInterruptId {
+    pub const COUNT: u32 = 12;
+
+    pub fn from_u32(id: u32) -> Option<LocalInterruptId> {
+        if id < Self::COUNT {
+            Some(unsafe { core::mem::transmute::<u32, LocalInterruptId>(id) })
+        } else {
+            None
+        }
+    }
+
+    pub fn mailbox(mb: usize) -> Option<LocalInterruptId> {
+        match mb {
+            0..=3 => Self::from_u32(LocalInterruptId::Mailbox0 as u32 + mb as u32),
+            _ => None,
+        }
+    }
+}
+
+pub struct LocalIntc {}
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    CONTROL: ReadWrite<u32>,                                        // 0x00
+    __reserved_0: u32,                                              // 0x04
+    CORE_TIMER_PRESCALER: ReadWrite<u32>,                           // 0x08
+    GPU_INT_ROUTING: ReadWrite<u32, GPU_INT_ROUTING::Register>,     // 0x0C
+    PMU_INT_ROUTING_SET: ReadWrite<u32>,                            // 0x10
+    PMU_INT_ROUTING_CLR: ReadWrite<u32>,                            // 0x14
+    __reserved_1: u32,                                              // 0x18
+    CORE_TIMER_LS: ReadWrite<u32>,                                  // 0x1C
+    CORE_TIMER_MS: ReadWrite<u32>,                                  // 0x20
+    LOCAL_INT_ROUTING: ReadWrite<u32, LOCAL_INT_ROUTING::Register>, // 0x24
+    __reserved_2: [u32; 3],                                         // 0x28
+    LOCAL_TIMER_CONTROL: ReadWrite<u32>,                            // 0x34
+    LOCAL_TIMER_CLEAR: WriteOnly<u32>,                              // 0x38
+    __reserved_3: u32,                                              // 0x3C
+    CORE_TIMER_INT_CONTROL: [ReadWrite<u32, INT_CONTROL::Register>; CORES], // 0x40
+    CORE_MAILBOX_INT_CONTROL: [ReadWrite<u32, INT_CONTROL::Register>; CORES], // 0x50
+    CORE_IRQ_SOURCE: [ReadOnly<u32>; CORES],                        // 0x60
+    CORE_FIQ_SOURCE: [ReadOnly<u32>; CORES],                        // 0x70
+    /// Write 1 to set bits. [core][mailbox]
+    MAILBOX_SET: [[WriteOnly<u32>; 4]; CORES], // 0x80
+    /// Read, write 1 to clear bits. [core][mailbox]
+    MAILBOX_CLR: [[ReadWrite<u32>; 4]; CORES], // 0xC0
+}
+
+register_bitfields! {
+    u32,
+    GPU_INT_ROUTING[
+        FIQ_CORE OFFSET(2) NUMBITS(2)[],
+        IRQ_CORE OFFSET(0) NUMBITS(2)[]
+    ],
+    LOCAL_INT_ROUTING[
+        /// 0-3: IRQ of core 0-3, 4-7: FIQ of core 0-3.
+        ROUTE OFFSET(0) NUMBITS(3)[]
+    ],
+    /// Common layout for core timers and mailboxes. Bit n is timer n or mailbox n.
+    /// FIQ bit takes precedence over IRQ bit.
+    INT_CONTROL[
+        FIQ OFFSET(4) NUMBITS(4)[],
+        IRQ OFFSET(0) NUMBITS(4)[]
+    ]
+}
+
+impl core::ops::Deref for LocalIntc {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*Self::ptr() }
+    }
+}
+
+/// Core number running this code.
+pub fn core_id() -> usize {
+    use cortex_a::regs::*;
+    (MPIDR_EL1.get() & 0x3) as usize
+}
+
+#[allow(dead_code)]
+impl LocalIntc {
+    pub fn new() -> LocalIntc {
+        LocalIntc {}
+    }
+
+    fn ptr() -> *const RegisterBlock {
+        LOCAL_INTC_BASE as *const _
+    }
+
+    pub fn get_irq_source(&self, core: usize) -> u32 {
+        if core >= CORES {
+            return 0;
+        }
+        self.CORE_IRQ_SOURCE[core].get()
+    }
+
+    pub fn get_fiq_source(&self, core: usize) -> u32 {
+        if core >= CORES {
+            return 0;
+        }
+        self.CORE_FIQ_SOURCE[core].get()
+    }
+
+    /// Deliver all interrupts of the legacy controller as IRQ to `core`.
+    pub fn route_gpu_irq(&self, core: usize) {
+        if core < CORES {
+            self.GPU_INT_ROUTING
+                .modify(GPU_INT_ROUTING::IRQ_CORE.val(core as u32));
+        }
+    }
+
+    /// Deliver the legacy controller's FIQ to `core`.
+    pub fn route_gpu_fiq(&self, core: usize) {
+        if core < CORES {
+            self.GPU_INT_ROUTING
+                .modify(GPU_INT_ROUTING::FIQ_CORE.val(core as u32));
+        }
+    }
+
+    pub fn get_gpu_irq_core(&self) -> usize {
+        self.GPU_INT_ROUTING.read(GPU_INT_ROUTING::IRQ_CORE) as usize
+    }
+
+    pub fn route_local_timer(&self, core: usize) {
+        if core < CORES {
+            self.LOCAL_INT_ROUTING
+                .write(LOCAL_INT_ROUTING::ROUTE.val(core as u32));
+        }
+    }
+
+    /// Register and bit for a timer or mailbox source. None for other sources.
+    fn control(
+        &self,
+        core: usize,
+        id: LocalInterruptId,
+    ) -> Option<(&ReadWrite<u32, INT_CONTROL::Register>, u32)> {
+        if core >= CORES {
+            return None;
+        }
+        let no = id as u32;
+        match id {
+            LocalInterruptId::CntPs
+            | LocalInterruptId::CntPns
+            | LocalInterruptId::CntHp
+            | LocalInterruptId::CntV => Some((&self.CORE_TIMER_INT_CONTROL[core], 1 << no)),
+            LocalInterruptId::Mailbox0
+            | LocalInterruptId::Mailbox1
+            | LocalInterruptId::Mailbox2
+            | LocalInterruptId::Mailbox3 => {
+                Some((&self.CORE_MAILBOX_INT_CONTROL[core], 1 << (no - 4)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Enable IRQ of a core timer or mailbox on `core`.
+    pub fn enable(&self, core: usize, id: LocalInterruptId) {
+        if let Some((reg, bit)) = self.control(core, id) {
+            reg.set(reg.get() | bit);
+        }
+    }
+
+    pub fn disable(&self, core: usize, id: LocalInterruptId) {
+        if let Some((reg, bit)) = self.control(core, id) {
+            reg.set(reg.get() & !(bit | bit << 4));
+        }
+    }
+
+    /// Set `bits` of `mailbox` of `core`. Raises its interrupt if enabled.
+    pub fn write_mailbox(&self, core: usize, mailbox: usize, bits: u32) {
+        if core < CORES && mailbox < 4 {
+            self.MAILBOX_SET[core][mailbox].set(bits);
+        }
+    }
+
+    pub fn read_mailbox(&self, core: usize, mailbox: usize) -> u32 {
+        if core < CORES && mailbox < 4 {
+            self.MAILBOX_CLR[core][mailbox].get()
+        } else {
+            0
+        }
+    }
+
+    pub fn clear_mailbox(&self, core: usize, mailbox: usize, bits: u32) {
+        if core < CORES && mailbox < 4 {
+            self.MAILBOX_CLR[core][mailbox].set(bits);
+        }
+    }
+
+    /// Deliver a core timer or mailbox as FIQ instead of IRQ.
+    pub fn route_to_fiq(&self, core: usize, id: LocalInterruptId) {
+        if let Some((reg, bit)) = self.control(core, id) {
+            reg.set((reg.get() & !bit) | bit << 4);
+        }
+    }
+}
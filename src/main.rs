@@ -27,32 +27,293 @@
 #![no_main]
 #![feature(asm)]
 #![feature(global_asm)]
-#![feature(new_uninit)]
 #![feature(const_fn)]
+#![feature(alloc_error_handler)]
 
-const MMIO_BASE: u32 = 0x3F00_0000;
+/// Physical address of the peripherals. Drivers reach them in the linear map at MMIO_BASE.
+const PERIPHERAL_BASE: u64 = 0x3F00_0000;
+const MMIO_BASE: u64 = mmu::KERNEL_VA_BASE + PERIPHERAL_BASE;
+
+/// Idle task has to come around within this period.
+const WATCHDOG_TIMEOUT_MS: u32 = 4000;
 
 mod arm_debug;
 mod arm_timer;
+mod bootargs;
+mod cmdline;
 mod dmac;
+mod elf;
+mod event;
 mod exception;
+mod fdt;
 mod gpio;
+mod heap_stats;
 mod interrupt;
+mod ipi;
+mod irq_dispatch;
+mod irq_stats;
+mod loader;
+mod local_intc;
 mod mbox;
+mod memory_map;
+mod mmu;
 mod optional_cell;
+mod stacks;
+mod sync;
+mod syscall;
+mod task;
+mod thread;
+mod thread_sync;
 mod timer;
 mod uart;
 mod utils;
+mod watchdog;
 
+use core::alloc::{GlobalAlloc, Layout};
 use nt_allocator::NtGlobalAlloc;
 extern crate alloc;
 
+/// Threads can be preempted in the middle of an allocation, and other cores allocate too:
+/// lock the allocator with IRQ masked.
+struct IrqSafeAlloc {
+    inner: NtGlobalAlloc,
+    lock: sync::Mutex<()>,
+}
+
+unsafe impl GlobalAlloc for IrqSafeAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _lock = self.lock.lock();
+        self.inner.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _lock = self.lock.lock();
+        self.inner.dealloc(ptr, layout)
+    }
+}
+
 #[global_allocator]
-static mut GLOBAL_ALLOCATOR: NtGlobalAlloc = NtGlobalAlloc {
-    base: 0x600_0000,
-    size: 0x200_0000,
+static mut GLOBAL_ALLOCATOR: heap_stats::TrackingAlloc<IrqSafeAlloc> = heap_stats::TrackingAlloc {
+    inner: IrqSafeAlloc {
+        // set from link.ld by `user_main`.
+        inner: NtGlobalAlloc { base: 0, size: 0 },
+        lock: sync::Mutex::new(()),
+    },
+};
+
+/// The allocator behind the counters.
+unsafe fn heap() -> &'static mut NtGlobalAlloc {
+    &mut GLOBAL_ALLOCATOR.inner.inner
+}
+
+fn dump_heap_stats(out: &dyn exception::ConsoleOut) {
+    unsafe {
+        let largest_free = GLOBAL_ALLOCATOR.largest_free(heap().size as usize);
+        heap_stats::dump(out, largest_free);
+    }
+}
+
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    let uart = uart::Uart::new();
+    uart.puts("[Heap] Failed to allocate ");
+    uart.hex(layout.size() as u32);
+    uart.puts(" bytes aligned to ");
+    uart.hex(layout.align() as u32);
+    uart.puts("\n");
+    dump_heap_stats(&uart);
+    loop {
+        unsafe { raspi3_boot::wfe() };
+    }
+}
+
+/// Bus address of the peripherals, as the VideoCore and the device tree's /soc see them.
+const PERIPHERAL_BUS_BASE: u64 = 0x7E00_0000;
+
+/// Shrink the heap to fit the RAM and reservations the device tree describes. It never grows
+/// beyond the built-in range, whose neighbours are laid out at compile time.
+fn fit_heap(fdt: &fdt::Fdt, base: u64, size: u64) -> u64 {
+    let ram_end = match fdt.memory().find(|r| base >= r.base && base < r.end()) {
+        Some(r) => r.end(),
+        None => return 0,
+    };
+    let mut end = core::cmp::min(base + size, ram_end);
+    for r in fdt.reservations().chain(core::iter::once(dtb_region(fdt))) {
+        if r.base <= base && r.end() > base {
+            return 0;
+        }
+        if r.base > base && r.base < end {
+            end = r.base;
+        }
+    }
+    end - base
+}
+
+/// Where the device tree blob is in physical memory.
+fn dtb_region(fdt: &fdt::Fdt) -> fdt::Region {
+    let r = fdt.region();
+    fdt::Region {
+        base: mmu::virt_to_phys(r.base),
+        size: r.size,
+    }
+}
+
+/// Read the device tree the firmware passed: report RAM, halt on another peripheral base and size
+/// the heap. Must run before the heap is initialized.
+unsafe fn apply_device_tree(uart: &uart::Uart) -> Option<fdt::Fdt<'static>> {
+    let fdt = match raspi3_boot::dtb_address().map(|a| fdt::Fdt::from_addr(mmu::phys_to_virt(a))) {
+        Some(Ok(fdt)) => fdt,
+        Some(Err(e)) => {
+            uart.puts("Bad device tree: ");
+            uart.puts(match e {
+                fdt::FdtError::BadMagic => "bad magic\n",
+                fdt::FdtError::BadVersion => "unsupported version\n",
+                fdt::FdtError::Truncated => "truncated\n",
+            });
+            return None;
+        }
+        None => {
+            uart.puts("No device tree, using built-in memory layout\n");
+            return None;
+        }
+    };
+
+    for r in fdt.memory() {
+        uart.puts("RAM ");
+        uart.hex(r.base as u32);
+        uart.puts(" - ");
+        uart.hex(r.end() as u32);
+        uart.puts("\n");
+    }
+
+    // drivers are built for PERIPHERAL_BASE, it can not be moved at runtime. On another base
+    // they would poke the wrong addresses, so stop here.
+    match fdt.soc_to_cpu(PERIPHERAL_BUS_BASE) {
+        Some(base) if base == PERIPHERAL_BASE => {}
+        Some(base) => {
+            uart.puts("Device tree puts peripherals at ");
+            uart.hex(base as u32);
+            uart.puts(", kernel is built for ");
+            uart.hex(PERIPHERAL_BASE as u32);
+            uart.puts(", halting\n");
+            loop {
+                raspi3_boot::wfe();
+            }
+        }
+        None => uart.puts("Device tree has no /soc ranges\n"),
+    }
+
+    let heap = heap();
+    let size = fit_heap(&fdt, mmu::virt_to_phys(heap.base as u64), heap.size as u64);
+    if size == 0 {
+        uart.puts("Heap is outside the RAM of the device tree, keeping it\n");
+    } else if size < heap.size as u64 {
+        uart.puts("Heap shrunk to ");
+        uart.hex(size as u32);
+        uart.puts(" bytes\n");
+        heap.size = size as _;
+    }
+    Some(fdt)
+}
+
+/// Burst length of the DMA benchmark. 0 for single transfers.
+static mut DMA_BURST: u8 = 0;
+
+static HEAP_PARAM: cmdline::Param = cmdline::Param {
+    name: "heap",
+    set: |v| {
+        // only shrinks, the heap is already fitted to its range.
+        let heap = unsafe { heap() };
+        match bootargs::parse_size(v)? {
+            0 => Err(cmdline::CmdlineError::BadValue),
+            size if size > heap.size as u64 => Err(cmdline::CmdlineError::OutOfRange),
+            size => {
+                heap.size = size as _;
+                Ok(())
+            }
+        }
+    },
+};
+
+static DMA_BURST_PARAM: cmdline::Param = cmdline::Param {
+    name: "dma.burst",
+    set: |v| match bootargs::parse_size(v)? {
+        b @ 0 | b @ 2 | b @ 4 | b @ 8 | b @ 16 => {
+            unsafe { DMA_BURST = b as u8 };
+            Ok(())
+        }
+        _ => Err(cmdline::CmdlineError::BadValue),
+    },
 };
 
+/// Read the kernel command line and apply the options of the kernel. Must run before the heap
+/// is initialized.
+unsafe fn apply_cmdline(fdt: Option<fdt::Fdt<'static>>, mbox: &mut mbox::Mbox, uart: &uart::Uart) {
+    for p in &[&HEAP_PARAM, &DMA_BURST_PARAM] {
+        if cmdline::register(*p).is_err() {
+            uart.puts("Failed to register kernel parameter ");
+            uart.puts(p.name);
+            uart.puts("\n");
+        }
+    }
+    cmdline::init(fdt.and_then(|f| f.bootargs()), mbox);
+    uart.puts("Command line: ");
+    uart.puts(cmdline::line());
+    uart.puts("\n");
+    cmdline::apply(uart);
+}
+
+/// Reserve the regions of link.ld with the heap as the allocator got it, the device tree and
+/// what it reserves, then show the map. Must run after `apply_cmdline`.
+unsafe fn init_memory_map(
+    fdt: Option<fdt::Fdt<'static>>,
+    mbox: &mut mbox::Mbox,
+    uart: &uart::Uart,
+) {
+    if let Err(e) = memory_map::init(mbox) {
+        uart.puts("Failed to build memory map: ");
+        report_memory_map_error(e, uart);
+        return;
+    }
+    let heap = heap();
+    let base = mmu::virt_to_phys(heap.base as u64);
+    let heap = (base, base + heap.size as u64);
+    let fixed = memory_map::fixed(heap);
+    let fixed = fixed.iter().map(|&(name, (start, end))| (name, start, end));
+    let firmware = fdt.into_iter().flat_map(|fdt| {
+        fdt.reservations()
+            .map(|r| ("firmware", r))
+            .chain(core::iter::once(("device tree", dtb_region(&fdt))))
+            .map(|(name, r)| (name, r.base, r.end()))
+    });
+    // report each failure and go on, the rest of the map is still useful.
+    for (name, start, end) in fixed.chain(firmware) {
+        if let Err(e) = memory_map::reserve(name, start, end) {
+            uart.puts("Failed to reserve ");
+            uart.puts(name);
+            uart.puts(": ");
+            report_memory_map_error(e, uart);
+        }
+    }
+    memory_map::print(uart);
+}
+
+fn report_memory_map_error(e: memory_map::MemoryMapError, uart: &uart::Uart) {
+    match e {
+        memory_map::MemoryMapError::MailboxError => uart.puts("mailbox error\n"),
+        memory_map::MemoryMapError::NoSpace => uart.puts("too many regions\n"),
+        memory_map::MemoryMapError::OutsideRam => uart.puts("outside RAM\n"),
+        memory_map::MemoryMapError::Overlap(other) => {
+            uart.puts("overlaps ");
+            uart.puts(other);
+            uart.puts("\n");
+        }
+    }
+}
+
+/// ARM timer ticks the thread scheduler at this rate.
+const THREAD_TICK_HZ: u32 = 20;
+
 #[allow(dead_code)]
 fn init(data_addr: u32, size: usize, init_data: u32) {
     for i in 0..size / 4 {
@@ -87,9 +348,62 @@ fn dump(data_addr: u32, size: usize, uart: &uart::Uart) {
     }
 }
 
+/// Written by each secondary core once it reaches `secondary_main`.
+static mut CORE_ONLINE: [bool; raspi3_boot::CORES] = [false; raspi3_boot::CORES];
+
+unsafe fn start_secondary_cores(uart: &uart::Uart) {
+    for core in 1..raspi3_boot::CORES {
+        if !raspi3_boot::start_core(core, secondary_main, stacks::el1_top(core)) {
+            uart.puts("Failed to start core ");
+            uart.hex(core as u32);
+            uart.puts("\n");
+        }
+    }
+
+    // wait a while for them to come up.
+    for _ in 0..1000 {
+        if (1..raspi3_boot::CORES).all(|c| core::ptr::read_volatile(&CORE_ONLINE[c])) {
+            break;
+        }
+        for _ in 0..1000 {
+            asm!("nop" :::: "volatile");
+        }
+    }
+    for core in 1..raspi3_boot::CORES {
+        if !core::ptr::read_volatile(&CORE_ONLINE[core]) {
+            uart.puts("Core ");
+            uart.hex(core as u32);
+            uart.puts(" did not respond\n");
+        }
+    }
+}
+
+extern "C" fn secondary_main(core: usize) -> ! {
+    unsafe {
+        // started only once core 0 built the kernel tables. this core still runs on the boot
+        // tables, which do not map thread stacks.
+        mmu::enable_on_this_core();
+        exception::set_vbar_el1();
+        ipi::enable_on_this_core();
+        raspi3_boot::enable_irq();
+        core::ptr::write_volatile(&mut CORE_ONLINE[core], true);
+    }
+
+    // woken up by IPIs.
+    loop {
+        unsafe {
+            raspi3_boot::wfi();
+        }
+    }
+}
+
+fn hello_from_core() {
+    uart::Uart::new().puts("[ipi] Hello from another core\n");
+}
+
 fn kernel_entry() {
     unsafe {
-        exception::el2_to_el1_transition(user_main as *const () as u64);
+        user_main();
     }
 }
 
@@ -99,26 +413,53 @@ unsafe fn user_main() -> ! {
     //let uart = uart::Uart::new();
     let uart = static_init!(uart::Uart, uart::Uart::new());
     let mut mbox = mbox::Mbox::new();
+    let watchdog = static_init!(watchdog::Watchdog, watchdog::Watchdog::new());
 
     // set up serial console
     match uart.init(&mut mbox) {
         Ok(_) => uart.puts("\n[0] UART is live!\n"),
-        Err(_) => loop {
-            asm!("wfe" :::: "volatile"); // If UART fails, abort early
-        },
+        Err(_) => watchdog.reboot(), // If UART fails, try again from a clean board
     }
 
-    GLOBAL_ALLOCATOR.init();
+    uart.puts("Booted at EL");
+    uart.hex(raspi3_boot::boot_el());
+    uart.puts("\n");
+
+    if watchdog.reset_by_watchdog() {
+        uart.puts("Last reset was caused by watchdog\n");
+    }
+    if watchdog.start(WATCHDOG_TIMEOUT_MS).is_err() {
+        uart.puts("Failed to start watchdog\n");
+    }
+    if watchdog.is_running() {
+        uart.puts("Watchdog resets in ms ");
+        uart.hex(watchdog.time_left());
+        uart.puts(" unless fed\n");
+    }
+
+    let (heap_start, heap_end) = memory_map::heap();
+    heap().base = mmu::phys_to_virt(heap_start) as _;
+    heap().size = (heap_end - heap_start) as _;
+    let fdt = apply_device_tree(uart);
+    apply_cmdline(fdt, &mut mbox, uart);
+    init_memory_map(fdt, &mut mbox, uart);
+    heap().init();
 
     let addr = exception::set_vbar_el1();
     uart.puts("set vbar");
     uart.hex((addr & 0xFFFF_FFFF) as u32);
     uart.puts("\n");
 
-    // Section 2.4, 2.5
-    let src = 0x200_0000;
-    let dest = 0x800_0000;
-    let size = 0x600_0000;
+    // before other cores start, they share the tables.
+    if mmu::init().is_err() {
+        uart.puts("Failed to build kernel tables\n");
+    }
+
+    // Section 2.4, 2.5. DMA takes physical addresses.
+    let (pool, pool_end) = memory_map::dma_pool();
+    let size = (pool_end - pool) / 2;
+    let src = pool as u32;
+    let dest = (pool + size) as u32;
 
     uart.puts("Initializing...\n");
 
@@ -131,94 +472,203 @@ unsafe fn user_main() -> ! {
     let timer = static_init!(timer::TIMER, timer::TIMER::new());
     let arm_timer = static_init!(arm_timer::ArmTimer, arm_timer::ArmTimer::new());
     let dma = static_init!(dmac::DMAC4, dmac::DMAC4::new());
+    let ipi = static_init!(ipi::Ipi, ipi::Ipi::new());
+    let ticker = static_init!(thread::Ticker, thread::Ticker::new());
+    let dma_done = static_init!(syscall::DmaDone, syscall::DmaDone::new());
 
     // setup irq handlers with drivers that have capability of irq handling.
-    setup_irq_handlers(timer, arm_timer, dma, uart);
+    setup_irq_handlers(timer, arm_timer, dma, ipi, ticker, dma_done, uart);
+    ipi::enable_on_this_core();
+
+    // they switch to the kernel tables first thing.
+    if mmu::is_enabled() {
+        start_secondary_cores(uart);
+    } else {
+        uart.puts("No kernel tables, secondary cores stay parked\n");
+    }
+
+    // legacy controller is served by core 0.
+    local_intc::LocalIntc::new().route_gpu_irq(0);
+    local_intc::LocalIntc::new().route_gpu_fiq(0);
 
     // enable interrupt handling at int controller.
     let int = interrupt::Interrupt::new();
-    int.enable_basic_irq(interrupt::BasicInterruptId::ARM_TIMER);
+    int.enable_basic_irq(interrupt::BasicInterruptId::ArmTimer);
     uart.puts("Enabling Irq1\n");
-    int.enable_irq(interrupt::InterruptId::TIMER1);
-    int.enable_irq(interrupt::InterruptId::DMA);
+    int.enable_irq(interrupt::InterruptId::SystemTimer1);
+    int.enable_irq(interrupt::InterruptId::Dma0);
+    int.enable_irq(interrupt::InterruptId::dma(syscall::DMA_CHANNEL).unwrap());
 
-    // enable receiving irq at CPU
+    // enable receiving irq and fiq at CPU
     raspi3_boot::enable_irq();
+    raspi3_boot::enable_fiq();
+
+    // call_on waits with IRQ unmasked.
+    for core in 1..raspi3_boot::CORES {
+        if !core::ptr::read_volatile(&CORE_ONLINE[core]) {
+            continue;
+        }
+        if !ipi::call_on(core, hello_from_core) {
+            uart.puts("IPI failed\n");
+        }
+    }
+
+    // this context becomes the main thread.
+    if thread::init(THREAD_TICK_HZ).is_err() {
+        uart.puts("Failed to start threads\n");
+    }
+    spawn_demo_threads(dma, uart);
+    syscall::init(uart, Some(dma));
+    spawn_user_demo(uart);
 
     // timer
     let current = timer.get_counter32();
-    let duration = 200_0000; // maybe 1sec.
     uart.puts("Starting timer\n");
-    timer.set(1, duration + current);
+    timer.set(1, TIMER_PERIOD + current);
+    timer.set(3, FIQ_TIMER_PERIOD + current);
 
     // arm timer
-    arm_timer.enable();
-    arm_timer.start_free_run();
-    arm_timer.enable_int();
-    arm_timer.set_count_down(1000000);
+    let arm_timer_config = arm_timer::ArmTimerConfig {
+        frequency: THREAD_TICK_HZ,
+        mode: arm_timer::TimerMode::Periodic,
+        width: arm_timer::CounterWidth::Bit32,
+        free_run_frequency: 1_000_000,
+        run_in_halt: false,
+    };
+    match arm_timer.configure(&arm_timer_config, &mut mbox) {
+        Ok(_) => {
+            arm_timer.enable_int();
+            arm_timer.enable();
+        }
+        Err(_) => uart.puts("Failed to configure arm timer\n"),
+    }
 
     // dma
-    let cb = dmac::ControlBlock4::new(src, dest, size as u32, 0);
+    let cb = dmac::ControlBlock4::new(src, dest, size as u32, DMA_BURST);
     dma.turn_on(0);
     dma.exec(0, &cb);
 
-    // main looooop
-    loop {
-        let mut context = MainTaskContext {
-            timer_occurred: false,
-            arm_timer_occurred: false,
-            dma_occurred: false,
-
-            timer: &timer,
-            arm_timer: &arm_timer,
-            dma: &dma,
-            uart: &uart,
-        };
-
-        {
-            // critical section start:
-            raspi3_boot::disable_irq();
-
-            context.timer_occurred = timer.occurred(1);
-            context.arm_timer_occurred = arm_timer.occurred();
-            context.dma_occurred = dma.occurred(0);
-
-            // critical section end
-            raspi3_boot::enable_irq();
-        }
+    spawn_demo_tasks(timer, arm_timer, dma, uart);
+
+    // the rest runs in tasks. this context becomes the idle task.
+    let watchdog: &'static watchdog::Watchdog = watchdog;
+    task::run(alloc::boxed::Box::leak(alloc::boxed::Box::new(move || {
+        // tasks are not stuck.
+        watchdog.feed();
+        // give the rest of the slice to other threads.
+        thread::yield_now();
+    })))
+}
 
-        // perform main task once.
-        main_task(context);
+/// EL0 demos, built from user/ by `make user`.
+static HELLO_ELF: &[u8] = include_bytes!("../user/hello.elf");
+static CRASH_ELF: &[u8] = include_bytes!("../user/crash.elf");
 
-        // sleep until interrupt.
-        raspi3_boot::wfi();
+fn spawn_user_demo(uart: &'static uart::Uart) {
+    let hello = loader::exec("el0 hello", HELLO_ELF, 0x4000, thread::DEFAULT_PRIORITY);
+    let crash = loader::exec("el0 crash", CRASH_ELF, 0x1000, thread::DEFAULT_PRIORITY);
+    if hello.is_err() || crash.is_err() {
+        uart.puts("Failed to spawn user threads\n");
     }
 }
 
-#[allow(dead_code)]
-struct MainTaskContext<'a> {
-    timer_occurred: bool,
-    arm_timer_occurred: bool,
-    dma_occurred: bool,
-
-    timer: &'a timer::TIMER,
-    arm_timer: &'a arm_timer::ArmTimer,
-    dma: &'a dmac::DMAC4,
-    uart: &'a uart::Uart,
-}
+fn spawn_demo_threads(dma: &'static dmac::DMAC4, uart: &'static uart::Uart) {
+    let done: &'static thread_sync::Queue<usize> =
+        alloc::boxed::Box::leak(alloc::boxed::Box::new(thread_sync::Queue::new(4)));
+    // only the benchmark's channel 0. system calls wait on their own channel.
+    dma.set_completion_queue(done, 1 << 0);
+    let dma_thread = thread::spawn(
+        "dma",
+        thread::DEFAULT_STACK_SIZE,
+        thread::DEFAULT_PRIORITY,
+        move || {
+            let ch = done.recv();
+            uart.puts("[dma thread] completed ch ");
+            uart.hex(ch as u32);
+            uart.puts("\n");
+        },
+    );
 
-fn main_task(context: MainTaskContext) {
-    if context.timer_occurred {
-        context.uart.puts("[main] Timer occurred ch1\n");
-        let current = context.timer.get_counter32();
-        let duration = 200_0000; // maybe 1sec.
-        context.timer.set(1, duration + current);
-    }
-    if context.arm_timer_occurred {
-        context.uart.puts("[main] Arm timer occurred\n");
+    let worker = thread::spawn(
+        "worker",
+        thread::DEFAULT_STACK_SIZE,
+        thread::DEFAULT_PRIORITY,
+        move || {
+            for _ in 0..3 {
+                thread::sleep(1000);
+                uart.puts("[worker] 1 sec passed\n");
+            }
+        },
+    );
+
+    let joiner = worker.and_then(|worker| {
+        thread::spawn(
+            "joiner",
+            thread::DEFAULT_STACK_SIZE,
+            thread::DEFAULT_PRIORITY,
+            move || {
+                if thread::join(worker).is_ok() {
+                    uart.puts("[joiner] worker finished\n");
+                }
+            },
+        )
+    });
+
+    if dma_thread.is_err() || joiner.is_err() {
+        uart.puts("Failed to spawn threads\n");
     }
-    if context.dma_occurred {
-        context.uart.puts("[main] DMA trans done.\n");
+}
+
+/// Period of the system timer demo. maybe 1sec.
+const TIMER_PERIOD: u32 = 200_0000;
+/// Period of the compare which fires as FIQ.
+const FIQ_TIMER_PERIOD: u32 = 500_0000;
+
+fn spawn_demo_tasks(
+    timer: &'static timer::TIMER,
+    arm_timer: &'static arm_timer::ArmTimer,
+    dma: &'static dmac::DMAC4,
+    uart: &'static uart::Uart,
+) {
+    let timer_task = task::spawn("timer", task::DEFAULT_STACK_SIZE, move || loop {
+        task::wait_flags(timer.events(), 1 << 1);
+        uart.puts("[timer] Timer occurred ch1\n");
+        let current = timer.get_counter32();
+        timer.set(1, TIMER_PERIOD + current);
+    });
+
+    let fiq_timer_task = task::spawn("fiq_timer", task::DEFAULT_STACK_SIZE, move || loop {
+        task::wait_flags(timer.events(), 1 << 3);
+        uart.puts("[timer] FIQ occurred ch3\n");
+        let current = timer.get_counter32();
+        timer.set(3, FIQ_TIMER_PERIOD + current);
+    });
+
+    let arm_timer_task = task::spawn("arm_timer", task::DEFAULT_STACK_SIZE, move || {
+        let mut ticks = 0;
+        loop {
+            // it also ticks threads. print once a sec.
+            ticks += task::wait_counter(arm_timer.events());
+            if ticks >= THREAD_TICK_HZ {
+                ticks -= THREAD_TICK_HZ;
+                uart.puts("[arm_timer] Arm timer occurred\n");
+            }
+        }
+    });
+
+    let dma_task = task::spawn("dma", task::DEFAULT_STACK_SIZE, move || {
+        task::wait_flags(dma.events(), 1 << 0);
+        uart.puts("[dma] DMA trans done.\n");
+        irq_stats::dump(uart);
+        dump_heap_stats(uart);
+    });
+
+    if timer_task.is_err()
+        || fiq_timer_task.is_err()
+        || arm_timer_task.is_err()
+        || dma_task.is_err()
+    {
+        uart.puts("Failed to spawn tasks\n");
     }
 }
 
@@ -226,37 +676,33 @@ unsafe fn setup_irq_handlers(
     timer: &'static timer::TIMER,
     arm_timer: &'static arm_timer::ArmTimer,
     dma: &'static dmac::DMAC4,
+    ipi: &'static ipi::Ipi,
+    ticker: &'static thread::Ticker,
+    dma_done: &'static syscall::DmaDone,
     uart: &'static uart::Uart,
 ) {
-    let timer_int_ids = static_init!(
-        [u32; 2],
-        [
-            interrupt::InterruptId::TIMER1,
-            interrupt::InterruptId::TIMER3
-        ]
-    );
-    let dma_int_ids = static_init!([u32; 1], [interrupt::InterruptId::DMA]);
-    let arm_timer_int_ids = static_init!([u32; 1], [interrupt::BasicInterruptId::ARM_TIMER]);
-
-    let irq_devices = static_init!(
-        [exception::IrqHandler; 2],
-        [
-            exception::IrqHandler::new(timer, timer_int_ids),
-            exception::IrqHandler::new(dma, dma_int_ids)
-        ]
-    );
-
-    let basic_irq_devices = static_init!(
-        [exception::IrqHandler; 1],
-        [exception::IrqHandler::new(arm_timer, arm_timer_int_ids)]
-    );
-
-    let handler_info = static_init!(
-        exception::IrqHandlersSettings,
-        exception::IrqHandlersSettings::new(irq_devices, basic_irq_devices)
-    );
-
-    let register_result = exception::set_irq_handlers(handler_info);
+    use interrupt::{BasicInterruptId, InterruptId, IrqSource};
+
+    let dma_syscall = IrqSource::Gpu(InterruptId::dma(syscall::DMA_CHANNEL).unwrap());
+
+    let register_result = irq_dispatch::register(IrqSource::Gpu(InterruptId::SystemTimer1), timer)
+        .and_then(|_| irq_dispatch::register(IrqSource::Gpu(InterruptId::Dma0), dma))
+        .and_then(|_| irq_dispatch::register(dma_syscall, dma))
+        // after dma, which clears the interrupt.
+        .and_then(|_| irq_dispatch::register(dma_syscall, dma_done))
+        .and_then(|_| irq_dispatch::register(ipi::SOURCE, ipi))
+        .and_then(|_| {
+            irq_dispatch::register(IrqSource::Basic(BasicInterruptId::ArmTimer), arm_timer)
+        })
+        // after arm_timer, which clears the interrupt.
+        .and_then(|_| irq_dispatch::register(IrqSource::Basic(BasicInterruptId::ArmTimer), ticker))
+        .is_ok();
+
+    // system timer must not wait for verbose handlers of others.
+    irq_dispatch::set_priority(IrqSource::Gpu(InterruptId::SystemTimer1), 0x40);
+
+    // compare 3 is served first of all, as FIQ. FIQ is still masked on the CPU.
+    irq_dispatch::register_fiq(IrqSource::Gpu(InterruptId::SystemTimer3), timer);
 
     let debug_context = static_init!(
         exception::DebugContext,
@@ -38,7 +38,7 @@ register_bitfields! {
     ]
 }
 
-const VIDEOCORE_MBOX: u32 = MMIO_BASE + 0xB880;
+const VIDEOCORE_MBOX: u64 = MMIO_BASE + 0xB880;
 
 #[allow(non_snake_case)]
 #[repr(C)]
@@ -65,13 +65,18 @@ pub mod channel {
 // Tags
 pub mod tag {
     pub const _GETSERIAL: u32 = 0x10004;
+    pub const GETARMMEMORY: u32 = 0x10005;
+    pub const GETCLKRATE: u32 = 0x30002;
     pub const SETCLKRATE: u32 = 0x38002;
+    pub const GETCMDLINE: u32 = 0x50001;
     pub const LAST: u32 = 0;
 }
 
 // Clocks
 pub mod clock {
     pub const UART: u32 = 0x0_0000_0002;
+    /// VPU core clock. The APB clock feeding the ARM timer is derived from it.
+    pub const CORE: u32 = 0x0_0000_0004;
 }
 
 // Responses
@@ -82,13 +87,16 @@ mod response {
 
 pub const REQUEST: u32 = 0;
 
+/// Large enough for a 1 KiB command line.
+pub const BUFFER_WORDS: usize = 264;
+
 // Public interface to the mailbox
 #[repr(C)]
 #[repr(align(16))]
 pub struct Mbox {
     // The address for buffer needs to be 16-byte aligned so that the
     // Videcore can handle it properly.
-    pub buffer: [u32; 36],
+    pub buffer: [u32; BUFFER_WORDS],
 }
 
 /// Deref to RegisterBlock
@@ -111,7 +119,9 @@ impl ops::Deref for Mbox {
 
 impl Mbox {
     pub fn new() -> Mbox {
-        Mbox { buffer: [0; 36] }
+        Mbox {
+            buffer: [0; BUFFER_WORDS],
+        }
     }
 
     /// Returns a pointer to the register block
@@ -130,7 +140,13 @@ impl Mbox {
             unsafe { asm!("nop" :::: "volatile") };
         }
 
-        let buf_ptr = self.buffer.as_ptr() as u32;
+        let buf_va = self.buffer.as_ptr() as u64;
+        let buf_len = core::mem::size_of_val(&self.buffer) as u64;
+        // the GPU only knows physical addresses.
+        let buf_ptr = crate::mmu::virt_to_phys(buf_va) as u32;
+
+        // the GPU reads and writes the buffer in memory, not in our cache.
+        crate::mmu::invalidate_dcache_range(buf_va, buf_len);
 
         // write the address of our message to the mailbox with channel identifier
         self.WRITE.set((buf_ptr & !0xF) | (channel & 0xF));
@@ -150,6 +166,7 @@ impl Mbox {
 
             // is it a response to our message?
             if ((resp & 0xF) == channel) && ((resp & !0xF) == buf_ptr) {
+                crate::mmu::invalidate_dcache_range(buf_va, buf_len);
                 // is it a valid successful response?
                 return match self.buffer[1] {
                     response::SUCCESS => Ok(()),
@@ -0,0 +1,182 @@
+//! Where things are in physical memory. Fixed regions come from link.ld, the end of the RAM the
+//! ARM may use from the firmware. Reservations must not overlap, what is left is free.
+
+use crate::mbox;
+use crate::mmu;
+use crate::uart::Uart;
+use core::sync::atomic::{compiler_fence, Ordering};
+
+const MAX_REGIONS: usize = 16;
+
+#[derive(Debug)]
+pub enum MemoryMapError {
+    MailboxError,
+    /// Every slot is in use.
+    NoSpace,
+    /// The region is not inside the RAM of the ARM.
+    OutsideRam,
+    /// The region overlaps the one named.
+    Overlap(&'static str),
+}
+pub type Result<T> = ::core::result::Result<T, MemoryMapError>;
+
+#[derive(Copy, Clone)]
+pub struct Region {
+    pub name: &'static str,
+    pub start: u64,
+    pub end: u64,
+}
+
+extern "C" {
+    static __ro_start: u64;
+    static __bss_end: u64;
+    static __stacks_start: u64;
+    static __stacks_end: u64;
+    static __dma_pool_start: u64;
+    static __dma_pool_end: u64;
+    static __heap_start: u64;
+    static __heap_end: u64;
+}
+
+/// link.ld places the symbols in the linear map.
+fn addr(sym: &u64) -> u64 {
+    mmu::virt_to_phys(sym as *const u64 as u64)
+}
+
+/// Sorted by start.
+static mut REGIONS: [Option<Region>; MAX_REGIONS] = [None; MAX_REGIONS];
+/// RAM of the ARM is 0..RAM_END, the GPU has the rest.
+static mut RAM_END: u64 = 0;
+
+/// Code, data and bss.
+pub fn image() -> (u64, u64) {
+    unsafe { (addr(&__ro_start), addr(&__bss_end)) }
+}
+
+/// Boot stacks with their guard pages.
+pub fn stacks() -> (u64, u64) {
+    unsafe { (addr(&__stacks_start), addr(&__stacks_end)) }
+}
+
+/// Buffers of the DMA benchmark. Non-cacheable.
+pub fn dma_pool() -> (u64, u64) {
+    unsafe { (addr(&__dma_pool_start), addr(&__dma_pool_end)) }
+}
+
+/// Range of the global allocator. Non-cacheable, so DMA control blocks can be boxed.
+pub fn heap() -> (u64, u64) {
+    unsafe { (addr(&__heap_start), addr(&__heap_end)) }
+}
+
+/// Base and size of the ARM's part of the memory.
+fn arm_memory(mbox: &mut mbox::Mbox) -> Result<(u64, u64)> {
+    mbox.buffer[0] = 8 * 4;
+    mbox.buffer[1] = mbox::REQUEST;
+    mbox.buffer[2] = mbox::tag::GETARMMEMORY;
+    mbox.buffer[3] = 8;
+    mbox.buffer[4] = 0;
+    mbox.buffer[5] = 0; // base is returned here.
+    mbox.buffer[6] = 0; // size is returned here.
+    mbox.buffer[7] = mbox::tag::LAST;
+    compiler_fence(Ordering::Release);
+
+    match mbox.call(mbox::channel::PROP) {
+        Ok(_) => Ok((u64::from(mbox.buffer[5]), u64::from(mbox.buffer[6]))),
+        Err(_) => Err(MemoryMapError::MailboxError),
+    }
+}
+
+/// Ask the firmware for the size of the RAM. Reserve `fixed` after.
+///
+/// # Safety
+///
+/// - Must not race with `reserve`: call while booting on core 0.
+pub unsafe fn init(mbox: &mut mbox::Mbox) -> Result<()> {
+    let (base, size) = arm_memory(mbox)?;
+    RAM_END = base + size;
+    Ok(())
+}
+
+/// Regions of link.ld and `mmu`. `heap` is the range the allocator got, which may be less than
+/// link.ld gives it; the rest is free.
+pub fn fixed(heap: (u64, u64)) -> [(&'static str, (u64, u64)); 5] {
+    [
+        ("kernel image", image()),
+        ("boot stacks", stacks()),
+        ("DMA pool", dma_pool()),
+        ("heap", heap),
+        ("user frames", mmu::frame_pool()),
+    ]
+}
+
+/// Claim `start..end`. Refused when it overlaps a reservation or leaves the RAM.
+///
+/// # Safety
+///
+/// - Same as `init`.
+pub unsafe fn reserve(name: &'static str, start: u64, end: u64) -> Result<()> {
+    if start >= end || end > RAM_END {
+        return Err(MemoryMapError::OutsideRam);
+    }
+    if let Some(r) = REGIONS
+        .iter()
+        .flatten()
+        .find(|r| start < r.end && r.start < end)
+    {
+        return Err(MemoryMapError::Overlap(r.name));
+    }
+
+    let count = REGIONS.iter().take_while(|r| r.is_some()).count();
+    if count == MAX_REGIONS {
+        return Err(MemoryMapError::NoSpace);
+    }
+    let at = REGIONS[..count]
+        .iter()
+        .flatten()
+        .position(|r| r.start > start)
+        .unwrap_or(count);
+    for i in (at..count).rev() {
+        REGIONS[i + 1] = REGIONS[i];
+    }
+    REGIONS[at] = Some(Region { name, start, end });
+    Ok(())
+}
+
+pub fn reserved() -> impl Iterator<Item = &'static Region> {
+    unsafe { REGIONS.iter().flatten() }
+}
+
+/// Gaps between the reservations, up to the end of the RAM.
+pub fn free() -> impl Iterator<Item = (u64, u64)> {
+    let ram_end = unsafe { RAM_END };
+    let mut last = 0;
+    reserved()
+        .map(|r| (r.start, r.end))
+        .chain(core::iter::once((ram_end, ram_end)))
+        .filter_map(move |(start, end)| {
+            let gap = (last, start);
+            last = core::cmp::max(last, end);
+            if gap.0 < gap.1 {
+                Some(gap)
+            } else {
+                None
+            }
+        })
+}
+
+pub fn print(uart: &Uart) {
+    let line = |start: u64, end: u64, name: &str| {
+        uart.hex(start as u32);
+        uart.puts(" - ");
+        uart.hex(end as u32);
+        uart.puts(" ");
+        uart.puts(name);
+        uart.puts("\n");
+    };
+    for r in reserved() {
+        line(r.start, r.end, r.name);
+    }
+    for (start, end) in free() {
+        line(start, end, "free");
+    }
+}
@@ -0,0 +1,775 @@
+//! Translation tables, 4 KiB granule and 39 bit virtual addresses.
+//!
+//! - TTBR1 maps the first 2 GiB of physical memory linearly from `KERNEL_VA_BASE`, EL1 only.
+//!   The kernel is linked there and runs from it, and reaches frames of user pages through it.
+//! - TTBR0 holds nothing but user pages, in [USER_VA_BASE, USER_VA_END), tagged with the address
+//!   space's ASID. Threads without an address space have an empty root.
+//! - Thread stacks live in TTBR1 from `KERNEL_STACKS_VA`, each at the top of its own 2 MiB slot
+//!   with the rest of the slot unmapped as its guard.
+//!
+//! `_boot_cores` turns the MMU on with tables of 2 MiB blocks, `init` replaces them.
+//!
+//! RAM used for DMA, including the heap which holds control blocks, is mapped non-cacheable.
+
+use crate::memory_map;
+use crate::stacks;
+use crate::sync::Mutex;
+
+pub const PAGE_SIZE: u64 = 0x1000;
+const ENTRIES: usize = 512;
+const VA_BITS: u64 = 39;
+const L1_SHIFT: u64 = 30;
+const L2_SHIFT: u64 = 21;
+const L3_SHIFT: u64 = 12;
+const BLOCK_SIZE: u64 = 1 << L2_SHIFT;
+
+pub const KERNEL_VA_BASE: u64 = raspi3_boot::KERNEL_VA_BASE;
+/// One L1 entry.
+pub const USER_VA_BASE: u64 = 0x10_0000_0000;
+pub const USER_VA_END: u64 = USER_VA_BASE + (1 << L1_SHIFT);
+/// Code and data of programs go below, the stack ends here.
+pub const USER_CODE_END: u64 = USER_VA_BASE + 0x3000_0000;
+pub const USER_STACK_TOP: u64 = USER_VA_END;
+
+/// Frames of user pages and their tables.
+const FRAME_POOL_BASE: u64 = 0x1000_0000;
+const FRAME_POOL_SIZE: u64 = 0x400_0000;
+const FRAMES: usize = (FRAME_POOL_SIZE / PAGE_SIZE) as usize;
+
+const DEVICE_START: u64 = crate::PERIPHERAL_BASE;
+/// Local peripherals fit in one block.
+const DEVICE_END: u64 = 0x4000_0000 + BLOCK_SIZE;
+/// Physical memory mapped by `LINEAR_L2`.
+const LINEAR_END: u64 = 2 << L1_SHIFT;
+
+pub const KERNEL_STACKS_VA: u64 = KERNEL_VA_BASE + LINEAR_END;
+const STACK_SLOT_SIZE: u64 = BLOCK_SIZE;
+const STACK_SLOTS: usize = 64;
+
+/// The image is mapped by pages so only text and rodata are executable, and read-only.
+const IMAGE_L3_TABLES: usize = 2;
+const IMAGE_MAPPED_END: u64 = IMAGE_L3_TABLES as u64 * BLOCK_SIZE;
+
+/// 256 ASIDs, 0 is the kernel's.
+const ASIDS: usize = 256;
+
+mod desc {
+    pub const VALID: u64 = 1 << 0;
+    /// Table at level 1 and 2, page at level 3. Clear for a block.
+    pub const TABLE: u64 = 1 << 1;
+    pub const PAGE: u64 = 1 << 1;
+    pub const ATTR_NORMAL: u64 = 0 << 2;
+    pub const ATTR_DEVICE: u64 = 1 << 2;
+    pub const ATTR_NON_CACHEABLE: u64 = 2 << 2;
+    pub const AP_EL0: u64 = 1 << 6;
+    pub const AP_RO: u64 = 1 << 7;
+    pub const SH_INNER: u64 = 3 << 8;
+    pub const AF: u64 = 1 << 10;
+    pub const NG: u64 = 1 << 11;
+    pub const PXN: u64 = 1 << 53;
+    pub const UXN: u64 = 1 << 54;
+    pub const ADDR_MASK: u64 = 0x0000_FFFF_FFFF_F000;
+}
+
+/// Attr0: normal write-back, Attr1: device nGnRnE, Attr2: normal non-cacheable.
+const MAIR: u64 = 0x44_00_FF;
+
+const TCR: u64 = (64 - VA_BITS)           // T0SZ
+    | 1 << 8 | 1 << 10 | 3 << 12          // walks of TTBR0: write-back, inner shareable
+    | (64 - VA_BITS) << 16                // T1SZ
+    | 1 << 24 | 1 << 26 | 3 << 28         // walks of TTBR1
+    | 2 << 30; // TG1 4 KiB. TG0 is 0 for 4 KiB, IPS 0 for 4 GiB.
+
+const CACHE_LINE: u64 = 64;
+
+pub type Prot = u32;
+pub const PROT_READ: Prot = 0x1;
+pub const PROT_WRITE: Prot = 0x2;
+pub const PROT_EXEC: Prot = 0x4;
+
+#[derive(Debug)]
+pub enum MmuError {
+    /// The image and its stacks do not fit in the page mapped part.
+    ImageTooLarge,
+    OutOfFrames,
+    OutOfAsids,
+    /// Outside the user range or not page aligned.
+    BadAddress,
+    AlreadyMapped,
+    NotMapped,
+}
+pub type Result<T> = ::core::result::Result<T, MmuError>;
+
+#[repr(C, align(4096))]
+struct Table([u64; ENTRIES]);
+
+impl Table {
+    const EMPTY: Table = Table([0; ENTRIES]);
+}
+
+/// TTBR0 of threads without an address space. Empty.
+static KERNEL_ROOT: Table = Table::EMPTY;
+/// TTBR1 of all cores.
+static mut HIGH_ROOT: Table = Table::EMPTY;
+static mut LINEAR_L2: [Table; 2] = [Table::EMPTY, Table::EMPTY];
+static mut IMAGE_L3: [Table; IMAGE_L3_TABLES] = [Table::EMPTY, Table::EMPTY];
+/// Slots of thread stacks. Their level 3 tables are kept once allocated.
+static mut STACKS_L2: Table = Table::EMPTY;
+static STACK_SLOTS_USED: Mutex<u64> = Mutex::new(0);
+
+static mut ENABLED: bool = false;
+
+struct FramePool {
+    used: [u64; FRAMES / 64],
+}
+
+static FRAME_POOL: Mutex<FramePool> = Mutex::new(FramePool {
+    used: [0; FRAMES / 64],
+});
+
+static ASID_POOL: Mutex<[u64; ASIDS / 64]> = Mutex::new([1, 0, 0, 0]);
+
+extern "C" {
+    static __ro_start: u64;
+    static __ro_end: u64;
+}
+
+fn table_desc(pa: u64) -> u64 {
+    pa | desc::VALID | desc::TABLE
+}
+
+fn addr_of<T>(t: &T) -> u64 {
+    t as *const T as u64
+}
+
+/// DMA test buffers and the heap.
+fn is_non_cacheable(pa: u64) -> bool {
+    let (dma, dma_end) = memory_map::dma_pool();
+    let (heap, heap_end) = memory_map::heap();
+    (pa >= dma && pa < dma_end) || (pa >= heap && pa < heap_end)
+}
+
+/// Attributes of the linear map at `pa` outside the image. None if unmapped.
+fn kernel_block_attrs(pa: u64) -> Option<u64> {
+    let normal = desc::SH_INNER | desc::AF | desc::PXN | desc::UXN;
+    if pa >= DEVICE_START && pa < DEVICE_END {
+        Some(desc::ATTR_DEVICE | desc::AF | desc::PXN | desc::UXN)
+    } else if is_non_cacheable(pa) {
+        Some(desc::ATTR_NON_CACHEABLE | normal)
+    } else if pa < DEVICE_START {
+        Some(desc::ATTR_NORMAL | normal)
+    } else {
+        None
+    }
+}
+
+unsafe fn build_linear() -> Result<()> {
+    let ro_start = virt_to_phys(&__ro_start as *const _ as u64);
+    let ro_end = virt_to_phys(&__ro_end as *const _ as u64);
+    if virt_to_phys(stacks::end()) > IMAGE_MAPPED_END {
+        return Err(MmuError::ImageTooLarge);
+    }
+
+    for (i, l3) in IMAGE_L3.iter_mut().enumerate() {
+        for (j, e) in l3.0.iter_mut().enumerate() {
+            let pa = (i as u64) << L2_SHIFT | (j as u64) << L3_SHIFT;
+            let common =
+                pa | desc::VALID | desc::PAGE | desc::ATTR_NORMAL | desc::SH_INNER | desc::AF;
+            *e = if pa >= ro_start && pa < ro_end {
+                common | desc::AP_RO | desc::UXN
+            } else {
+                common | desc::PXN | desc::UXN
+            };
+        }
+    }
+
+    for guard in stacks::guard_pages().map(virt_to_phys) {
+        IMAGE_L3[(guard >> L2_SHIFT) as usize].0[(guard >> L3_SHIFT) as usize % ENTRIES] = 0;
+    }
+
+    for i in 0..(LINEAR_END / BLOCK_SIZE) as usize {
+        let pa = (i as u64) << L2_SHIFT;
+        let e = if pa < IMAGE_MAPPED_END {
+            table_desc(virt_to_phys(addr_of(&IMAGE_L3[i])))
+        } else {
+            match kernel_block_attrs(pa) {
+                Some(attrs) => pa | desc::VALID | attrs,
+                None => 0,
+            }
+        };
+        LINEAR_L2[i / ENTRIES].0[i % ENTRIES] = e;
+    }
+
+    for i in 0..2 {
+        HIGH_ROOT.0[i] = table_desc(virt_to_phys(addr_of(&LINEAR_L2[i])));
+    }
+    HIGH_ROOT.0[(LINEAR_END >> L1_SHIFT) as usize] = table_desc(virt_to_phys(addr_of(&STACKS_L2)));
+    Ok(())
+}
+
+/// Build the kernel tables and switch this core to them.
+///
+/// # Safety
+///
+/// - Call once, on core 0, before other cores start.
+pub unsafe fn init() -> Result<()> {
+    build_linear()?;
+    enable_on_this_core();
+    ENABLED = true;
+    Ok(())
+}
+
+/// Switch from the boot tables to the ones built by `init`. The MMU and caches are already on.
+///
+/// # Safety
+///
+/// - `init` must have succeeded. Call first thing on secondary cores.
+pub unsafe fn enable_on_this_core() {
+    asm!("msr mair_el1, $0
+          msr tcr_el1, $1
+          isb" :: "r"(MAIR), "r"(TCR) : "memory" : "volatile");
+    // the boot tables identity map the switch, so it must happen before TTBR0 goes.
+    raspi3_boot::replace_ttbr1(virt_to_phys(addr_of(&HIGH_ROOT)));
+    asm!("msr ttbr0_el1, $0
+          isb
+          tlbi vmalle1
+          dsb nsh
+          isb" :: "r"(kernel_ttbr0()) : "memory" : "volatile");
+}
+
+pub fn is_enabled() -> bool {
+    unsafe { ENABLED }
+}
+
+/// TTBR0 of threads without an address space. ASID 0.
+pub fn kernel_ttbr0() -> u64 {
+    virt_to_phys(addr_of(&KERNEL_ROOT))
+}
+
+/// Install `ttbr0` on this core if it is not there yet.
+///
+/// # Safety
+///
+/// - Call with IRQ masked. Nothing may be using the outgoing user mapping.
+pub unsafe fn switch_ttbr0(ttbr0: u64) {
+    if !ENABLED {
+        return;
+    }
+    let cur: u64;
+    asm!("mrs $0, ttbr0_el1" : "=r"(cur) ::: "volatile");
+    if cur != ttbr0 {
+        asm!("msr ttbr0_el1, $0
+              isb" :: "r"(ttbr0) : "memory" : "volatile");
+    }
+}
+
+/// Address of physical `pa` in the TTBR1 linear map.
+pub fn phys_to_virt(pa: u64) -> u64 {
+    KERNEL_VA_BASE + pa
+}
+
+/// Physical address of `va` in the linear map or on a thread stack.
+pub fn virt_to_phys(va: u64) -> u64 {
+    if !is_kernel_stack(va) {
+        return va - KERNEL_VA_BASE;
+    }
+    let par: u64;
+    unsafe {
+        asm!("at s1e1r, $1
+              isb
+              mrs $0, par_el1" : "=r"(par) : "r"(va) :: "volatile");
+    }
+    (par & desc::ADDR_MASK) | va % PAGE_SIZE
+}
+
+/// Range of the frames `alloc_frames` hands out.
+pub fn frame_pool() -> (u64, u64) {
+    (FRAME_POOL_BASE, FRAME_POOL_BASE + FRAME_POOL_SIZE)
+}
+
+fn is_pool_frame(pa: u64) -> bool {
+    pa >= FRAME_POOL_BASE && pa < FRAME_POOL_BASE + FRAME_POOL_SIZE
+}
+
+/// `count` physically contiguous zeroed frames.
+pub fn alloc_frames(count: usize) -> Result<u64> {
+    if count == 0 {
+        return Err(MmuError::OutOfFrames);
+    }
+    let first = {
+        let mut pool = FRAME_POOL.lock();
+        let is_used = |used: &[u64], i: usize| used[i / 64] & 1 << (i % 64) != 0;
+        let mut run = 0;
+        let mut found = None;
+        for i in 0..FRAMES {
+            run = if is_used(&pool.used, i) { 0 } else { run + 1 };
+            if run == count {
+                found = Some(i + 1 - count);
+                break;
+            }
+        }
+        let first = found.ok_or(MmuError::OutOfFrames)?;
+        for i in first..first + count {
+            pool.used[i / 64] |= 1 << (i % 64);
+        }
+        first
+    };
+
+    let pa = FRAME_POOL_BASE + first as u64 * PAGE_SIZE;
+    unsafe {
+        core::ptr::write_bytes(phys_to_virt(pa) as *mut u8, 0, count * PAGE_SIZE as usize);
+    }
+    Ok(pa)
+}
+
+pub fn free_frames(pa: u64, count: usize) {
+    if !is_pool_frame(pa) {
+        return;
+    }
+    let first = ((pa - FRAME_POOL_BASE) / PAGE_SIZE) as usize;
+    let mut pool = FRAME_POOL.lock();
+    for i in first..core::cmp::min(first + count, FRAMES) {
+        pool.used[i / 64] &= !(1 << (i % 64));
+    }
+}
+
+fn alloc_asid() -> Result<u16> {
+    let mut pool = ASID_POOL.lock();
+    for (w, bits) in pool.iter_mut().enumerate() {
+        if *bits != !0 {
+            let b = (!*bits).trailing_zeros() as usize;
+            *bits |= 1 << b;
+            return Ok((w * 64 + b) as u16);
+        }
+    }
+    Err(MmuError::OutOfAsids)
+}
+
+fn free_asid(asid: u16) {
+    let asid = asid as usize;
+    if asid != 0 && asid < ASIDS {
+        ASID_POOL.lock()[asid / 64] &= !(1 << (asid % 64));
+    }
+}
+
+fn user_page_attrs(prot: Prot) -> u64 {
+    let mut d = desc::VALID
+        | desc::PAGE
+        | desc::ATTR_NORMAL
+        | desc::SH_INNER
+        | desc::AF
+        | desc::NG
+        | desc::AP_EL0
+        | desc::PXN;
+    if prot & PROT_WRITE == 0 {
+        d |= desc::AP_RO;
+    }
+    if prot & PROT_EXEC == 0 {
+        d |= desc::UXN;
+    }
+    d
+}
+
+fn prot_of(d: u64) -> Prot {
+    let mut prot = PROT_READ;
+    if d & desc::AP_RO == 0 {
+        prot |= PROT_WRITE;
+    }
+    if d & desc::UXN == 0 {
+        prot |= PROT_EXEC;
+    }
+    prot
+}
+
+unsafe fn table_at(pa: u64) -> &'static mut [u64; ENTRIES] {
+    &mut *(phys_to_virt(pa) as *mut [u64; ENTRIES])
+}
+
+fn page_range(va: u64, len: u64) -> Result<(u64, u64)> {
+    let end = match va.checked_add(len) {
+        Some(end) => (end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1),
+        None => return Err(MmuError::BadAddress),
+    };
+    let start = va & !(PAGE_SIZE - 1);
+    if len == 0 || start < USER_VA_BASE || end > USER_VA_END {
+        return Err(MmuError::BadAddress);
+    }
+    Ok((start, end))
+}
+
+unsafe fn flush_tlb_page(asid: u16, va: u64) {
+    let arg = (asid as u64) << 48 | (va >> 12) & 0xFFF_FFFF_FFFF;
+    asm!("dsb ishst
+          tlbi vae1is, $0
+          dsb ish
+          isb" :: "r"(arg) : "memory" : "volatile");
+}
+
+unsafe fn flush_tlb_asid(asid: u16) {
+    asm!("dsb ishst
+          tlbi aside1is, $0
+          dsb ish
+          isb" :: "r"((asid as u64) << 48) : "memory" : "volatile");
+}
+
+/// Global entries, such as thread stacks, in all ASIDs.
+unsafe fn flush_tlb_kernel_page(va: u64) {
+    asm!("dsb ishst
+          tlbi vaae1is, $0
+          dsb ish
+          isb" :: "r"((va >> 12) & 0xFFF_FFFF_FFFF) : "memory" : "volatile");
+}
+
+fn release_stack_slot(slot: usize) {
+    *STACK_SLOTS_USED.lock() &= !(1 << slot);
+}
+
+/// Map a thread stack of `size` bytes at the top of a free slot. Returns its bottom.
+pub fn alloc_kernel_stack(size: usize) -> Result<u64> {
+    let pages = ((size as u64 + PAGE_SIZE - 1) / PAGE_SIZE) as usize;
+    // keep at least one page of guard.
+    if !is_enabled() || pages == 0 || pages >= ENTRIES {
+        return Err(MmuError::BadAddress);
+    }
+    let slot = {
+        let mut used = STACK_SLOTS_USED.lock();
+        let slot = (!*used).trailing_zeros() as usize;
+        if slot >= STACK_SLOTS {
+            return Err(MmuError::OutOfFrames);
+        }
+        *used |= 1 << slot;
+        slot
+    };
+    let pa = match alloc_frames(pages) {
+        Ok(pa) => pa,
+        Err(e) => {
+            release_stack_slot(slot);
+            return Err(e);
+        }
+    };
+
+    unsafe {
+        let l2e = &mut STACKS_L2.0[slot];
+        if *l2e & desc::VALID == 0 {
+            match alloc_frames(1) {
+                Ok(t) => *l2e = table_desc(t),
+                Err(e) => {
+                    free_frames(pa, pages);
+                    release_stack_slot(slot);
+                    return Err(e);
+                }
+            }
+        }
+        let l3 = table_at(*l2e & desc::ADDR_MASK);
+        let first = ENTRIES - pages;
+        for i in 0..pages {
+            l3[first + i] = (pa + i as u64 * PAGE_SIZE)
+                | desc::VALID
+                | desc::PAGE
+                | desc::ATTR_NORMAL
+                | desc::SH_INNER
+                | desc::AF
+                | desc::PXN
+                | desc::UXN;
+        }
+        asm!("dsb ishst
+              isb" :::: "volatile");
+        Ok(KERNEL_STACKS_VA + slot as u64 * STACK_SLOT_SIZE + first as u64 * PAGE_SIZE)
+    }
+}
+
+/// Unmap a stack from `alloc_kernel_stack` and free its frames.
+///
+/// # Safety
+///
+/// - Nobody may run on it any more.
+pub unsafe fn free_kernel_stack(bottom: u64) {
+    if !is_kernel_stack(bottom) {
+        return;
+    }
+    let slot = ((bottom - KERNEL_STACKS_VA) / STACK_SLOT_SIZE) as usize;
+    let l2e = STACKS_L2.0[slot];
+    if l2e & desc::VALID == 0 {
+        return;
+    }
+    let l3 = table_at(l2e & desc::ADDR_MASK);
+    let first = ((bottom >> L3_SHIFT) as usize) % ENTRIES;
+    let pa = l3[first] & desc::ADDR_MASK;
+    for i in first..ENTRIES {
+        l3[i] = 0;
+        flush_tlb_kernel_page(bottom + (i - first) as u64 * PAGE_SIZE);
+    }
+    free_frames(pa, ENTRIES - first);
+    release_stack_slot(slot);
+}
+
+pub fn is_kernel_stack(addr: u64) -> bool {
+    addr >= KERNEL_STACKS_VA && addr < KERNEL_STACKS_VA + STACK_SLOTS as u64 * STACK_SLOT_SIZE
+}
+
+/// Unmapped range below the stack at `bottom` from `alloc_kernel_stack`.
+pub fn stack_guard(bottom: u64) -> Option<(u64, u64)> {
+    if is_kernel_stack(bottom) {
+        Some((bottom & !(STACK_SLOT_SIZE - 1), bottom))
+    } else {
+        None
+    }
+}
+
+/// User half of a process. Frames from the pool mapped into it are freed with it.
+pub struct AddressSpace {
+    root: u64,
+    asid: u16,
+}
+
+#[allow(dead_code)]
+impl AddressSpace {
+    pub fn new() -> Result<AddressSpace> {
+        if !is_enabled() {
+            return Err(MmuError::NotMapped);
+        }
+        let asid = alloc_asid()?;
+        // the frame is zeroed, the kernel is not in TTBR0.
+        let root = match alloc_frames(1) {
+            Ok(r) => r,
+            Err(e) => {
+                free_asid(asid);
+                return Err(e);
+            }
+        };
+        Ok(AddressSpace { root, asid })
+    }
+
+    pub fn ttbr0(&self) -> u64 {
+        self.root | (self.asid as u64) << 48
+    }
+
+    pub fn asid(&self) -> u16 {
+        self.asid
+    }
+
+    /// Level 3 entry of `va`. Missing tables are allocated if `create`.
+    fn entry(&self, va: u64, create: bool) -> Result<&'static mut u64> {
+        if va < USER_VA_BASE || va >= USER_VA_END {
+            return Err(MmuError::BadAddress);
+        }
+        let mut table = self.root;
+        for &shift in [L1_SHIFT, L2_SHIFT].iter() {
+            let e = unsafe { &mut table_at(table)[(va >> shift) as usize % ENTRIES] };
+            if *e & desc::VALID == 0 {
+                if !create {
+                    return Err(MmuError::NotMapped);
+                }
+                *e = table_desc(alloc_frames(1)?);
+            }
+            table = *e & desc::ADDR_MASK;
+        }
+        Ok(unsafe { &mut table_at(table)[(va >> L3_SHIFT) as usize % ENTRIES] })
+    }
+
+    /// Map the page at `va` to the frame at `pa`.
+    pub fn map(&mut self, va: u64, pa: u64, prot: Prot) -> Result<()> {
+        if va % PAGE_SIZE != 0 || pa % PAGE_SIZE != 0 {
+            return Err(MmuError::BadAddress);
+        }
+        let e = self.entry(va, true)?;
+        if *e & desc::VALID != 0 {
+            return Err(MmuError::AlreadyMapped);
+        }
+        *e = pa | user_page_attrs(prot);
+        unsafe { asm!("dsb ishst" :::: "volatile") };
+        Ok(())
+    }
+
+    /// Back [va, va + len) with physically contiguous new frames, so DMA can reach it.
+    pub fn map_new(&mut self, va: u64, len: u64, prot: Prot) -> Result<()> {
+        let (start, end) = page_range(va, len)?;
+        let count = ((end - start) / PAGE_SIZE) as usize;
+        let pa = alloc_frames(count)?;
+        for i in 0..count as u64 {
+            if let Err(e) = self.map(start + i * PAGE_SIZE, pa + i * PAGE_SIZE, prot) {
+                for j in 0..i {
+                    let _ = self.unmap(start + j * PAGE_SIZE);
+                }
+                free_frames(pa, count);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the page at `va` and return its frame, which the caller now owns.
+    pub fn unmap(&mut self, va: u64) -> Result<u64> {
+        let e = self.entry(va & !(PAGE_SIZE - 1), false)?;
+        if *e & desc::VALID == 0 {
+            return Err(MmuError::NotMapped);
+        }
+        let pa = *e & desc::ADDR_MASK;
+        *e = 0;
+        unsafe { flush_tlb_page(self.asid, va) };
+        Ok(pa)
+    }
+
+    /// Change permissions of the mapped pages in [va, va + len).
+    pub fn protect(&mut self, va: u64, len: u64, prot: Prot) -> Result<()> {
+        let (start, end) = page_range(va, len)?;
+        let mut page = start;
+        while page < end {
+            let e = self.entry(page, false)?;
+            if *e & desc::VALID == 0 {
+                return Err(MmuError::NotMapped);
+            }
+            *e = (*e & desc::ADDR_MASK) | user_page_attrs(prot);
+            unsafe { flush_tlb_page(self.asid, page) };
+            page += PAGE_SIZE;
+        }
+        Ok(())
+    }
+
+    /// Physical address and permissions of `va`.
+    pub fn translate(&self, va: u64) -> Option<(u64, Prot)> {
+        let e = self.entry(va & !(PAGE_SIZE - 1), false).ok()?;
+        if *e & desc::VALID == 0 {
+            return None;
+        }
+        Some(((*e & desc::ADDR_MASK) | va % PAGE_SIZE, prot_of(*e)))
+    }
+
+    /// Copy `data` to `va` through the linear map, whatever the user permissions are.
+    pub fn copy_to(&mut self, va: u64, data: &[u8]) -> Result<()> {
+        let mut done = 0;
+        while done < data.len() {
+            let cur = va + done as u64;
+            let chunk = core::cmp::min(data.len() - done, (PAGE_SIZE - cur % PAGE_SIZE) as usize);
+            let (pa, _) = self.translate(cur).ok_or(MmuError::NotMapped)?;
+            let dest = phys_to_virt(pa);
+            unsafe {
+                core::ptr::copy_nonoverlapping(data[done..].as_ptr(), dest as *mut u8, chunk);
+            }
+            clean_dcache_range(dest, chunk as u64);
+            done += chunk;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        unsafe {
+            let l1 = table_at(self.root);
+            for l1e in l1[(USER_VA_BASE >> L1_SHIFT) as usize..].iter_mut() {
+                if *l1e & desc::VALID == 0 {
+                    continue;
+                }
+                let l2 = table_at(*l1e & desc::ADDR_MASK);
+                for l2e in l2.iter() {
+                    if *l2e & desc::VALID == 0 {
+                        continue;
+                    }
+                    for l3e in table_at(*l2e & desc::ADDR_MASK).iter() {
+                        if *l3e & desc::VALID != 0 {
+                            free_frames(*l3e & desc::ADDR_MASK, 1);
+                        }
+                    }
+                    free_frames(*l2e & desc::ADDR_MASK, 1);
+                }
+                free_frames(*l1e & desc::ADDR_MASK, 1);
+            }
+            flush_tlb_asid(self.asid);
+        }
+        free_frames(self.root, 1);
+        free_asid(self.asid);
+    }
+}
+
+/// Physical address of `va` if EL0 of the current address space may access it.
+pub fn user_phys(va: u64, write: bool) -> Option<u64> {
+    // only the user range is looked up, whatever else EL0 might reach.
+    if !is_enabled() || va < USER_VA_BASE || va >= USER_VA_END {
+        return None;
+    }
+    let par: u64;
+    unsafe {
+        if write {
+            asm!("at s1e0w, $1
+                  isb
+                  mrs $0, par_el1" : "=r"(par) : "r"(va) :: "volatile");
+        } else {
+            asm!("at s1e0r, $1
+                  isb
+                  mrs $0, par_el1" : "=r"(par) : "r"(va) :: "volatile");
+        }
+    }
+    if par & 1 != 0 {
+        return None;
+    }
+    Some((par & desc::ADDR_MASK) | va % PAGE_SIZE)
+}
+
+/// Physical address of [va, va + len) if EL0 may access all of it and it is contiguous.
+pub fn user_range_phys(va: u64, len: u64, write: bool) -> Option<u64> {
+    let end = va.checked_add(len)?;
+    if len == 0 {
+        return None;
+    }
+    let first = user_phys(va, write)?;
+    let mut page = (va & !(PAGE_SIZE - 1)) + PAGE_SIZE;
+    while page < end {
+        if user_phys(page, write)? != first + (page - va) {
+            return None;
+        }
+        page += PAGE_SIZE;
+    }
+    Some(first)
+}
+
+fn dcache_op_range(va: u64, len: u64, op: fn(u64)) {
+    let mut line = va & !(CACHE_LINE - 1);
+    while line < va + len {
+        op(line);
+        line += CACHE_LINE;
+    }
+    unsafe { asm!("dsb sy" :::: "volatile") };
+}
+
+/// Write dirty lines of [va, va + len) back so that DMA and instruction fetch see them.
+pub fn clean_dcache_range(va: u64, len: u64) {
+    dcache_op_range(va, len, |l| unsafe {
+        asm!("dc cvac, $0" :: "r"(l) :: "volatile")
+    });
+}
+
+/// Write back and drop lines of [va, va + len) so that data written by DMA is read from memory.
+pub fn invalidate_dcache_range(va: u64, len: u64) {
+    dcache_op_range(va, len, |l| unsafe {
+        asm!("dc civac, $0" :: "r"(l) :: "volatile")
+    });
+}
+
+pub fn invalidate_icache() {
+    unsafe {
+        asm!("ic iallu
+              dsb sy
+              isb" :::: "volatile");
+    }
+}
+
+/// Describe an instruction or data abort, e.g. "translation fault, level 3, on write".
+pub fn describe_abort(esr: u64) -> Option<(&'static str, u64, Option<bool>)> {
+    let ec = (esr >> 26) & 0x3F;
+    let write = match ec {
+        0x20 | 0x21 => None,
+        0x24 | 0x25 => Some(esr & (1 << 6) != 0),
+        _ => return None,
+    };
+    let fsc = esr & 0x3F;
+    let kind = match fsc >> 2 {
+        0b0000 => "address size fault",
+        0b0001 => "translation fault",
+        0b0010 => "access flag fault",
+        0b0011 => "permission fault",
+        _ if fsc == 0x21 => "alignment fault",
+        _ => "abort",
+    };
+    Some((kind, fsc & 0x3, write))
+}
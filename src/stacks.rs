@@ -0,0 +1,69 @@
+//! Boot stacks defined in link.ld. Each sits above a guard page which `mmu` leaves unmapped,
+//! so running off the bottom raises a data abort with FAR_EL1 in the guard.
+//!
+//! Thread stacks get their guards from `mmu::alloc_kernel_stack`.
+
+use crate::local_intc::CORES;
+use crate::mmu::PAGE_SIZE;
+
+extern "C" {
+    static __el2_stack_bottom: u64;
+    static __el1_stack0_bottom: u64;
+    static __el1_stack0_top: u64;
+    static __el1_stack1_bottom: u64;
+    static __el1_stack1_top: u64;
+    static __el1_stack2_bottom: u64;
+    static __el1_stack2_top: u64;
+    static __el1_stack3_bottom: u64;
+    static __el1_stack3_top: u64;
+    static __stacks_end: u64;
+}
+
+fn addr(sym: &u64) -> u64 {
+    sym as *const u64 as u64
+}
+
+/// Top of the EL1 stack of `core`. Core 0 enters EL1 on it from `_boot_cores`.
+pub fn el1_top(core: usize) -> u64 {
+    unsafe {
+        match core {
+            0 => addr(&__el1_stack0_top),
+            1 => addr(&__el1_stack1_top),
+            2 => addr(&__el1_stack2_top),
+            _ => addr(&__el1_stack3_top),
+        }
+    }
+}
+
+/// Bottoms of the stacks with the names reported on overflow.
+fn bottoms() -> [(u64, &'static str); CORES + 1] {
+    unsafe {
+        [
+            // EL2 runs with its MMU off, so only EL1 accesses to this guard trap.
+            (addr(&__el2_stack_bottom), "EL2 boot stack"),
+            (addr(&__el1_stack0_bottom), "EL1 stack of core 0"),
+            (addr(&__el1_stack1_bottom), "EL1 stack of core 1"),
+            (addr(&__el1_stack2_bottom), "EL1 stack of core 2"),
+            (addr(&__el1_stack3_bottom), "EL1 stack of core 3"),
+        ]
+    }
+}
+
+/// Guard pages to leave unmapped.
+pub fn guard_pages() -> impl Iterator<Item = u64> {
+    let b = bottoms();
+    (0..b.len()).map(move |i| b[i].0 - PAGE_SIZE)
+}
+
+/// End of the stacks area, for checking it is covered by the page mapped image.
+pub fn end() -> u64 {
+    unsafe { addr(&__stacks_end) }
+}
+
+/// Name of the stack whose guard page contains `addr`.
+pub fn guard_owner(addr: u64) -> Option<&'static str> {
+    bottoms()
+        .iter()
+        .find(|(bottom, _)| addr >= bottom - PAGE_SIZE && addr < *bottom)
+        .map(|&(_, name)| name)
+}
@@ -0,0 +1,187 @@
+//! Locks for state shared between interrupt and thread context, and between cores.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+/// Run `f` with IRQ and FIQ masked on this core. The previous mask is restored afterwards,
+/// so it can be nested and called from IRQ or FIQ context.
+pub fn critical_section<R, F: FnOnce() -> R>(f: F) -> R {
+    let daif = unsafe { raspi3_boot::save_and_disable_interrupts() };
+    let r = f();
+    unsafe { raspi3_boot::restore_interrupts(daif) };
+    r
+}
+
+/// Exclusive load/store hang on memory which is not cacheable. Secondary cores are started only
+/// once `mmu::init` built the kernel tables, so core 0 is alone before and does without them.
+fn exclusives_work() -> bool {
+    crate::mmu::is_enabled()
+}
+
+/// Replace the value of `a` by `f` of it and return the old one. `f` may be called again
+/// if another core wins the race.
+pub fn update_u32<F: Fn(u32) -> u32>(a: &AtomicU32, f: F) -> u32 {
+    if !exclusives_work() {
+        return critical_section(|| {
+            let old = a.load(Ordering::Relaxed);
+            a.store(f(old), Ordering::Relaxed);
+            old
+        });
+    }
+    let mut old = a.load(Ordering::Relaxed);
+    loop {
+        match a.compare_exchange_weak(old, f(old), Ordering::AcqRel, Ordering::Relaxed) {
+            Ok(v) => return v,
+            Err(v) => old = v,
+        }
+    }
+}
+
+/// Store `new` in `a` if it holds `current`. Returns whether it did.
+pub fn compare_and_set_usize(a: &AtomicUsize, current: usize, new: usize) -> bool {
+    if !exclusives_work() {
+        return critical_section(|| {
+            let ok = a.load(Ordering::Relaxed) == current;
+            if ok {
+                a.store(new, Ordering::Relaxed);
+            }
+            ok
+        });
+    }
+    a.compare_exchange(current, new, Ordering::AcqRel, Ordering::Relaxed)
+        .is_ok()
+}
+
+/// Ticket lock usable across cores. First come, first served.
+///
+/// Built on exclusive load/store (ldaxr/stlxr), which only work on cacheable
+/// memory: enable MMU and caches before taking it from more than one core.
+/// It does not mask interrupts. Use `Mutex` for data also touched by IRQ or FIQ handlers.
+pub struct SpinLock<T> {
+    next: AtomicU32,
+    serving: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+#[allow(dead_code)]
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> SpinLock<T> {
+        SpinLock {
+            next: AtomicU32::new(0),
+            serving: AtomicU32::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed);
+        while self.serving.load(Ordering::Acquire) != ticket {
+            // woken up by sev in unlock.
+            unsafe { raspi3_boot::wfe() };
+        }
+        SpinLockGuard { lock: self }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        let serving = self.serving.load(Ordering::Acquire);
+        match self.next.compare_exchange(
+            serving,
+            serving.wrapping_add(1),
+            Ordering::Acquire,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => Some(SpinLockGuard { lock: self }),
+            Err(_) => None,
+        }
+    }
+
+    fn unlock(&self) {
+        self.serving.fetch_add(1, Ordering::Release);
+        unsafe { asm!("dsb ish\n sev" :::: "volatile") };
+    }
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.unlock();
+    }
+}
+
+/// Masks IRQ and FIQ on this core, then takes a `SpinLock` against other cores.
+/// Safe to use from IRQ and FIQ handlers and threads. DAIF is restored on unlock.
+///
+/// Before the MMU is on, core 0 runs alone and masking is enough: the `SpinLock` is skipped.
+pub struct Mutex<T> {
+    inner: SpinLock<T>,
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+    // dropped before restoring DAIF. None when the spin lock was skipped.
+    guard: Option<SpinLockGuard<'a, T>>,
+    daif: u64,
+}
+
+#[allow(dead_code)]
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Mutex<T> {
+        Mutex {
+            inner: SpinLock::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<T> {
+        let daif = unsafe { raspi3_boot::save_and_disable_interrupts() };
+        MutexGuard {
+            mutex: self,
+            guard: if exclusives_work() {
+                Some(self.inner.lock())
+            } else {
+                None
+            },
+            daif,
+        }
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.inner.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.inner.data.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.guard.take();
+        unsafe { raspi3_boot::restore_interrupts(self.daif) };
+    }
+}
@@ -0,0 +1,178 @@
+//! System calls from EL0.
+//!
+//! `svc #0` with the number in x8 and arguments in x0-x5. The result is returned in x0,
+//! values from `error` on failure.
+
+use crate::dmac::{ControlBlock4, DMAC4};
+use crate::exception::{ConsoleOut, ExceptionContext, InterruptionSource};
+use crate::interrupt::IrqSource;
+use crate::mmu;
+use crate::thread;
+use alloc::boxed::Box;
+
+pub mod number {
+    /// write(ptr, len) -> len
+    pub const WRITE: u64 = 0;
+    /// sleep(ms)
+    pub const SLEEP: u64 = 1;
+    /// exit(code). Never returns.
+    pub const EXIT: u64 = 2;
+    /// yield()
+    pub const YIELD: u64 = 3;
+    /// dma_copy(src, dest, len). Returns once the transfer is done.
+    pub const DMA_COPY: u64 = 4;
+}
+
+pub mod error {
+    pub const INVALID_ARGUMENT: u64 = -1i64 as u64;
+    pub const NO_SYSCALL: u64 = -2i64 as u64;
+    pub const BUSY: u64 = -3i64 as u64;
+    pub const NOT_SUPPORTED: u64 = -4i64 as u64;
+}
+
+/// DMA channel reserved for `DMA_COPY`.
+pub const DMA_CHANNEL: usize = 1;
+
+static mut CONSOLE: Option<&'static dyn ConsoleOut> = None;
+static mut DMA: Option<&'static DMAC4> = None;
+/// Control block of the transfer in flight. DMA reads it while running.
+static mut DMA_CB: Option<Box<ControlBlock4>> = None;
+/// Physical address and length written by the transfer in flight.
+static mut DMA_DEST: (u64, u64) = (0, 0);
+
+/// Wait queue of threads in `DMA_COPY`.
+fn dma_key() -> usize {
+    unsafe { &DMA_CB as *const _ as usize }
+}
+
+/// Give the devices system calls use. `dma` needs `DmaDone` registered to `DMA_CHANNEL`'s line.
+///
+/// # Safety
+///
+/// - Call before starting EL0 threads.
+pub unsafe fn init(console: &'static dyn ConsoleOut, dma: Option<&'static DMAC4>) {
+    CONSOLE = Some(console);
+    DMA = dma;
+}
+
+/// Whether EL0 of the caller's address space may access all of [ptr, ptr + len).
+fn is_user_range(ptr: u64, len: u64, write: bool) -> bool {
+    let end = match ptr.checked_add(len) {
+        Some(end) => end,
+        None => return false,
+    };
+    let mut page = ptr & !(mmu::PAGE_SIZE - 1);
+    while page < end {
+        if mmu::user_phys(page, write).is_none() {
+            return false;
+        }
+        page += mmu::PAGE_SIZE;
+    }
+    ptr != 0
+}
+
+/// Serve `svc` from EL0 and return the context to go back to.
+///
+/// # Safety
+///
+/// - Call from the synchronous exception vector of the lower EL, with IRQ masked.
+pub unsafe fn dispatch(e: &mut ExceptionContext) -> *mut ExceptionContext {
+    let ret = match e.gpr(8) {
+        number::WRITE => write(e.gpr(0), e.gpr(1)),
+        number::SLEEP => {
+            let ms = e.gpr(0) as u32;
+            return thread::sleep_from(e, ms);
+        }
+        number::EXIT => return thread::exit_from(e),
+        number::YIELD => {
+            e.set_gpr(0, 0);
+            return thread::switch(e, true);
+        }
+        number::DMA_COPY => match dma_copy(e.gpr(0), e.gpr(1), e.gpr(2)) {
+            // back here once `DmaDone` wakes it up.
+            Ok(()) => {
+                e.set_gpr(0, 0);
+                return thread::block_from(e, dma_key());
+            }
+            Err(err) => err,
+        },
+        _ => error::NO_SYSCALL,
+    };
+    e.set_gpr(0, ret);
+    e
+}
+
+unsafe fn write(ptr: u64, len: u64) -> u64 {
+    if !is_user_range(ptr, len, false) {
+        return error::INVALID_ARGUMENT;
+    }
+    let bytes = core::slice::from_raw_parts(ptr as *const u8, len as usize);
+    match (core::str::from_utf8(bytes), CONSOLE) {
+        (Ok(s), Some(console)) => {
+            console.puts(s);
+            len
+        }
+        (Err(_), _) => error::INVALID_ARGUMENT,
+        (_, None) => error::NOT_SUPPORTED,
+    }
+}
+
+unsafe fn dma_copy(src: u64, dest: u64, len: u64) -> core::result::Result<(), u64> {
+    let dma = match DMA {
+        Some(d) => d,
+        None => return Err(error::NOT_SUPPORTED),
+    };
+    if len > 0x3FFF_FFFF {
+        return Err(error::INVALID_ARGUMENT);
+    }
+    // DMA sees physical memory, so each buffer must be contiguous there.
+    let (src_pa, dest_pa) = match (
+        mmu::user_range_phys(src, len, false),
+        mmu::user_range_phys(dest, len, true),
+    ) {
+        (Some(s), Some(d)) => (s, d),
+        _ => return Err(error::INVALID_ARGUMENT),
+    };
+    if DMA_CB.is_some() {
+        return Err(error::BUSY);
+    }
+
+    mmu::clean_dcache_range(src, len);
+    mmu::invalidate_dcache_range(dest, len);
+    let cb = Box::new(ControlBlock4::new(
+        src_pa as u32,
+        dest_pa as u32,
+        len as u32,
+        0,
+    ));
+    dma.turn_on(DMA_CHANNEL);
+    dma.exec(DMA_CHANNEL, &cb);
+    DMA_CB = Some(cb);
+    DMA_DEST = (dest_pa, len);
+    Ok(())
+}
+
+/// Wakes up the thread waiting in `DMA_COPY`. Register after `DMAC4`, which clears the interrupt.
+pub struct DmaDone {}
+
+impl DmaDone {
+    pub fn new() -> DmaDone {
+        DmaDone {}
+    }
+}
+
+impl InterruptionSource for DmaDone {
+    fn on_interruption(&self, _source: IrqSource) {
+        unsafe {
+            if let Some(dma) = DMA {
+                if dma.events().take(1 << DMA_CHANNEL) != 0 {
+                    // drop lines the CPU fetched while the transfer ran.
+                    let (pa, len) = DMA_DEST;
+                    mmu::invalidate_dcache_range(mmu::phys_to_virt(pa), len);
+                    DMA_CB = None;
+                    thread::wake_all(dma_key());
+                }
+            }
+        }
+    }
+}
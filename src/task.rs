@@ -0,0 +1,289 @@
+//! Cooperative scheduler. Tasks run on core 0 until they yield, block on an event or return.
+
+use crate::event::{EventCounter, EventFlags};
+use crate::sync::critical_section;
+use alloc::alloc::{alloc, dealloc, Layout};
+use alloc::boxed::Box;
+
+global_asm!(include_str!("task.S"));
+
+/// Including the idle task.
+const MAX_TASKS: usize = 16;
+pub const DEFAULT_STACK_SIZE: usize = 0x4000;
+
+/// The caller of `run` becomes the idle task.
+const IDLE: TaskId = 0;
+
+pub type TaskId = usize;
+
+/// x19-x30, sp and d8-d15. Shared with task.S.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Context {
+    regs: [u64; 12],
+    sp: u64,
+    fp: [u64; 8],
+}
+
+impl Context {
+    const EMPTY: Context = Context {
+        regs: [0; 12],
+        sp: 0,
+        fp: [0; 8],
+    };
+}
+
+#[derive(Copy, Clone)]
+enum Wait {
+    Flags(&'static EventFlags, u32),
+    Counter(&'static EventCounter),
+}
+
+impl Wait {
+    fn is_satisfied(&self) -> bool {
+        match *self {
+            Wait::Flags(flags, mask) => flags.peek(mask) != 0,
+            Wait::Counter(counter) => counter.peek() != 0,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+enum State {
+    Ready,
+    Blocked(Wait),
+    /// Returned. Its stack is freed once another task runs.
+    Done,
+}
+
+#[derive(Copy, Clone)]
+struct Task {
+    name: &'static str,
+    context: Context,
+    state: State,
+    /// Null for the idle task, which runs on the boot stack.
+    stack: *mut u8,
+    stack_size: usize,
+}
+
+impl Task {
+    fn is_runnable(&self) -> bool {
+        match self.state {
+            State::Ready => true,
+            State::Blocked(wait) => wait.is_satisfied(),
+            State::Done => false,
+        }
+    }
+}
+
+pub enum TaskError {
+    NoSpace,
+    OutOfMemory,
+}
+pub type Result<T> = ::core::result::Result<T, TaskError>;
+
+static mut TASKS: [Option<Task>; MAX_TASKS] = [None; MAX_TASKS];
+static mut CURRENT: TaskId = IDLE;
+static mut IDLE_HOOK: Option<&'static dyn Fn()> = None;
+
+extern "C" {
+    fn __task_switch(save: *mut Context, load: *const Context);
+    fn __task_trampoline();
+}
+
+/// Create a task which starts at the next switch. Core 0 only.
+pub fn spawn<F: FnOnce() + 'static>(name: &'static str, stack_size: usize, f: F) -> Result<TaskId> {
+    unsafe {
+        reap();
+        let id = match (1..MAX_TASKS).find(|&i| TASKS[i].is_none()) {
+            Some(id) => id,
+            None => return Err(TaskError::NoSpace),
+        };
+
+        let layout = match Layout::from_size_align(stack_size, 16) {
+            Ok(l) => l,
+            Err(_) => return Err(TaskError::OutOfMemory),
+        };
+        let stack = alloc(layout);
+        if stack.is_null() {
+            return Err(TaskError::OutOfMemory);
+        }
+
+        let entry: Box<Box<dyn FnOnce()>> = Box::new(Box::new(f));
+        let mut context = Context::EMPTY;
+        context.regs[0] = Box::into_raw(entry) as u64; // x19
+        context.regs[11] = __task_trampoline as usize as u64; // lr
+        context.sp = (stack as u64 + stack_size as u64) & !0xF;
+
+        TASKS[id] = Some(Task {
+            name,
+            context,
+            state: State::Ready,
+            stack,
+            stack_size,
+        });
+        Ok(id)
+    }
+}
+
+#[no_mangle]
+extern "C" fn __task_start(entry: *mut Box<dyn FnOnce()>) -> ! {
+    let f = unsafe { Box::from_raw(entry) };
+    f();
+    exit();
+}
+
+/// Turn the caller into the idle task and start switching. Core 0 only.
+/// `idle_hook` is called every time no other task is runnable.
+pub fn run(idle_hook: &'static dyn Fn()) -> ! {
+    unsafe {
+        IDLE_HOOK = Some(idle_hook);
+        TASKS[IDLE] = Some(Task {
+            name: "idle",
+            context: Context::EMPTY,
+            state: State::Ready,
+            stack: core::ptr::null_mut(),
+            stack_size: 0,
+        });
+        CURRENT = IDLE;
+    }
+
+    loop {
+        if let Some(hook) = unsafe { IDLE_HOOK } {
+            hook();
+        }
+        // IRQ masked between the check and wfi, or a wake up may slip in between.
+        critical_section(|| {
+            if !others_runnable() {
+                unsafe { raspi3_boot::wfi() };
+            }
+        });
+        yield_now();
+    }
+}
+
+/// Let other ready tasks run. Must not be called with IRQ masked.
+pub fn yield_now() {
+    schedule();
+}
+
+/// Block until a flag in `mask` is set, then take them.
+pub fn wait_flags(flags: &'static EventFlags, mask: u32) -> u32 {
+    loop {
+        let v = flags.take(mask);
+        if v != 0 {
+            return v;
+        }
+        block(Wait::Flags(flags, mask));
+    }
+}
+
+/// Block until `counter` is signalled, then take the count.
+pub fn wait_counter(counter: &'static EventCounter) -> u32 {
+    loop {
+        let v = counter.take();
+        if v != 0 {
+            return v;
+        }
+        block(Wait::Counter(counter));
+    }
+}
+
+/// Finish the running task. Same as returning from its entry.
+pub fn exit() -> ! {
+    unsafe {
+        if let Some(t) = TASKS[CURRENT].as_mut() {
+            t.state = State::Done;
+        }
+    }
+    schedule();
+    // never switched back.
+    loop {
+        unsafe { raspi3_boot::wfe() };
+    }
+}
+
+#[allow(dead_code)]
+pub fn current() -> TaskId {
+    unsafe { CURRENT }
+}
+
+#[allow(dead_code)]
+pub fn name(id: TaskId) -> Option<&'static str> {
+    if id >= MAX_TASKS {
+        return None;
+    }
+    unsafe { TASKS[id].map(|t| t.name) }
+}
+
+fn block(wait: Wait) {
+    unsafe {
+        if let Some(t) = TASKS[CURRENT].as_mut() {
+            t.state = State::Blocked(wait);
+        }
+    }
+    schedule();
+}
+
+fn others_runnable() -> bool {
+    (0..MAX_TASKS)
+        .filter(|&i| i != IDLE)
+        .any(|i| unsafe { TASKS[i].map_or(false, |t| t.is_runnable()) })
+}
+
+/// Round robin among runnable tasks. Idle runs only when nobody else can.
+fn pick_next() -> TaskId {
+    let current = unsafe { CURRENT };
+    for i in 1..=MAX_TASKS {
+        let id = (current + i) % MAX_TASKS;
+        if id == IDLE {
+            continue;
+        }
+        if let Some(t) = unsafe { TASKS[id].as_mut() } {
+            if t.is_runnable() {
+                t.state = State::Ready;
+                return id;
+            }
+        }
+    }
+    IDLE
+}
+
+fn schedule() {
+    unsafe {
+        reap();
+        let prev = CURRENT;
+        let next = pick_next();
+        if next == prev {
+            return;
+        }
+
+        let save = match TASKS[prev].as_mut() {
+            Some(t) => &mut t.context as *mut Context,
+            None => return,
+        };
+        let load = match TASKS[next].as_ref() {
+            Some(t) => &t.context as *const Context,
+            None => return,
+        };
+        CURRENT = next;
+        __task_switch(save, load);
+    }
+}
+
+/// Free stacks of finished tasks. The running one is still on its stack.
+unsafe fn reap() {
+    for id in 0..MAX_TASKS {
+        if id == CURRENT {
+            continue;
+        }
+        if let Some(t) = TASKS[id] {
+            if let State::Done = t.state {
+                if !t.stack.is_null() {
+                    dealloc(t.stack, Layout::from_size_align_unchecked(t.stack_size, 16));
+                }
+                TASKS[id] = None;
+            }
+        }
+    }
+}
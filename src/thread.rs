@@ -0,0 +1,555 @@
+//! Preemptive threads on core 0.
+//!
+//! A thread is switched out on the timer tick, or when it yields, sleeps, joins or exits,
+//! by making the exception vector restore another thread's saved `ExceptionContext`.
+//! The caller of `init` becomes the main thread.
+
+use crate::exception::{self, ExceptionContext, InterruptionSource};
+use crate::interrupt::IrqSource;
+use crate::local_intc;
+use crate::mmu::{self, AddressSpace};
+use crate::sync::critical_section;
+use alloc::alloc::{alloc, dealloc, Layout};
+use alloc::boxed::Box;
+
+/// Including main and idle.
+const MAX_THREADS: usize = 16;
+pub const DEFAULT_STACK_SIZE: usize = 0x4000;
+
+/// Slot of the thread and its generation, so the id of a reaped thread never refers to a later
+/// thread which got the same slot.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ThreadId {
+    slot: usize,
+    generation: u32,
+}
+
+/// Smaller value runs first. Threads with the same priority take turns every tick.
+pub type Priority = u8;
+pub const DEFAULT_PRIORITY: Priority = 0x80;
+const IDLE_PRIORITY: Priority = 0xFF;
+
+/// Slot of the main thread.
+const MAIN: usize = 0;
+
+#[derive(Copy, Clone, PartialEq)]
+enum State {
+    Ready,
+    /// Until the tick count reaches this.
+    Sleeping(u64),
+    Joining(ThreadId),
+    /// On a wait queue, identified by the address of the object waited on.
+    Blocked(usize),
+    /// Returned. Its slot and stack are reused by a later `spawn`.
+    Done,
+}
+
+#[derive(Copy, Clone)]
+struct Thread {
+    name: &'static str,
+    /// Raised above `base_priority` while holding a mutex a higher one waits for.
+    priority: Priority,
+    base_priority: Priority,
+    state: State,
+    /// Saved on the thread's own stack while it is not running.
+    context: *mut ExceptionContext,
+    /// Null for the main thread, which keeps the boot stack.
+    stack: *mut u8,
+    stack_size: usize,
+    /// Installed in TTBR0 while running. Null for kernel threads, which only see the kernel.
+    space: *mut AddressSpace,
+    generation: u32,
+}
+
+pub enum ThreadError {
+    NotInitialized,
+    NoSpace,
+    OutOfMemory,
+    InvalidThread,
+}
+pub type Result<T> = ::core::result::Result<T, ThreadError>;
+
+static mut THREADS: [Option<Thread>; MAX_THREADS] = [None; MAX_THREADS];
+/// Bumped each time a slot is taken.
+static mut GENERATIONS: [u32; MAX_THREADS] = [0; MAX_THREADS];
+/// Slot of the running thread.
+static mut CURRENT: usize = MAIN;
+static mut TICKS: u64 = 0;
+static mut TICK_HZ: u32 = 0;
+static mut NEED_RESCHED: bool = false;
+
+/// Counts ticks and requests a switch. Register it to the line of the timer which ticks at
+/// the frequency given to `init`, after the timer driver which clears the interrupt.
+pub struct Ticker {}
+
+impl Ticker {
+    pub fn new() -> Ticker {
+        Ticker {}
+    }
+}
+
+impl InterruptionSource for Ticker {
+    fn on_interruption(&self, _source: IrqSource) {
+        unsafe {
+            TICKS += 1;
+            NEED_RESCHED = true;
+        }
+    }
+}
+
+/// Make the caller the main thread and start the idle thread.
+///
+/// # Safety
+///
+/// - Call once, on core 0.
+pub unsafe fn init(tick_hz: u32) -> Result<()> {
+    TICK_HZ = tick_hz;
+    THREADS[MAIN] = Some(Thread {
+        name: "main",
+        priority: DEFAULT_PRIORITY,
+        base_priority: DEFAULT_PRIORITY,
+        state: State::Ready,
+        context: core::ptr::null_mut(),
+        stack: core::ptr::null_mut(),
+        stack_size: 0,
+        space: core::ptr::null_mut(),
+        generation: GENERATIONS[MAIN],
+    });
+    CURRENT = MAIN;
+
+    // runs when everybody else sleeps. IRQ handlers run on its stack too.
+    spawn("idle", DEFAULT_STACK_SIZE, IDLE_PRIORITY, || loop {
+        unsafe { raspi3_boot::wfi() };
+    })
+    .map(|_| ())
+}
+
+/// Create a thread. It runs from the next switch on, preempting the caller if `priority` is higher.
+pub fn spawn<F: FnOnce() + 'static>(
+    name: &'static str,
+    stack_size: usize,
+    priority: Priority,
+    f: F,
+) -> Result<ThreadId> {
+    let entry: Box<Box<dyn FnOnce()>> = Box::new(Box::new(f));
+    let arg = Box::into_raw(entry);
+    let r = create(name, stack_size, priority, None, |top| unsafe {
+        exception::new_thread_context(top, thread_start as usize as u64, arg as u64)
+    });
+    if r.is_err() {
+        drop(unsafe { Box::from_raw(arg) });
+    }
+    r
+}
+
+/// Create a thread running `entry(arg)` at EL0 in `space`, which a program is loaded into, on a
+/// stack of `user_stack_size` ending at `mmu::USER_STACK_TOP`. It talks to the kernel through
+/// `svc`, see `syscall`. The stack is mapped into `space`, which is dropped when the thread is
+/// reaped.
+pub fn spawn_in(
+    name: &'static str,
+    mut space: Box<AddressSpace>,
+    entry: u64,
+    arg: usize,
+    user_stack_size: usize,
+    priority: Priority,
+) -> Result<ThreadId> {
+    let size = (user_stack_size as u64 + mmu::PAGE_SIZE - 1) & !(mmu::PAGE_SIZE - 1);
+    if space
+        .map_new(
+            mmu::USER_STACK_TOP - size,
+            size,
+            mmu::PROT_READ | mmu::PROT_WRITE,
+        )
+        .is_err()
+    {
+        return Err(ThreadError::OutOfMemory);
+    }
+    create(
+        name,
+        DEFAULT_STACK_SIZE,
+        priority,
+        Some(space),
+        |top| unsafe { exception::new_user_context(top, entry, arg as u64, mmu::USER_STACK_TOP) },
+    )
+}
+
+/// Allocate a stack and a slot. `context` lays out the first context given the stack top.
+fn create<C: FnOnce(u64) -> *mut ExceptionContext>(
+    name: &'static str,
+    stack_size: usize,
+    priority: Priority,
+    space: Option<Box<AddressSpace>>,
+    context: C,
+) -> Result<ThreadId> {
+    critical_section(|| unsafe {
+        if THREADS[MAIN].is_none() {
+            return Err(ThreadError::NotInitialized);
+        }
+        reap();
+        let slot = match (0..MAX_THREADS).find(|&i| THREADS[i].is_none()) {
+            Some(slot) => slot,
+            None => return Err(ThreadError::NoSpace),
+        };
+
+        let stack = alloc_stack(stack_size)?;
+        let context = context(stack as u64 + stack_size as u64);
+        GENERATIONS[slot] = GENERATIONS[slot].wrapping_add(1);
+        let id = ThreadId {
+            slot,
+            generation: GENERATIONS[slot],
+        };
+        THREADS[slot] = Some(Thread {
+            name,
+            priority,
+            base_priority: priority,
+            state: State::Ready,
+            context,
+            stack,
+            stack_size,
+            space: space.map_or(core::ptr::null_mut(), Box::into_raw),
+            generation: id.generation,
+        });
+        Ok(id)
+    })
+}
+
+/// Stacks have a guard below them once the kernel tables are built.
+unsafe fn alloc_stack(size: usize) -> Result<*mut u8> {
+    if mmu::is_enabled() {
+        return match mmu::alloc_kernel_stack(size) {
+            Ok(bottom) => Ok(bottom as *mut u8),
+            Err(_) => Err(ThreadError::OutOfMemory),
+        };
+    }
+    let layout = match Layout::from_size_align(size, 16) {
+        Ok(l) => l,
+        Err(_) => return Err(ThreadError::OutOfMemory),
+    };
+    let stack = alloc(layout);
+    if stack.is_null() {
+        return Err(ThreadError::OutOfMemory);
+    }
+    Ok(stack)
+}
+
+unsafe fn free_stack(stack: *mut u8, size: usize) {
+    if mmu::is_kernel_stack(stack as u64) {
+        mmu::free_kernel_stack(stack as u64);
+    } else if !stack.is_null() {
+        dealloc(stack, Layout::from_size_align_unchecked(size, 16));
+    }
+}
+
+extern "C" fn thread_start(entry: *mut Box<dyn FnOnce()>) -> ! {
+    let f = unsafe { Box::from_raw(entry) };
+    f();
+    exit();
+}
+
+/// Finish the running thread. Same as returning from its entry.
+pub fn exit() -> ! {
+    set_state(State::Done);
+    reschedule();
+    // never switched back.
+    loop {
+        unsafe { raspi3_boot::wfe() };
+    }
+}
+
+/// Let other threads of the same priority run.
+pub fn yield_now() {
+    reschedule();
+}
+
+/// Sleep for at least `ms`, rounded up to ticks.
+pub fn sleep(ms: u32) {
+    critical_section(|| set_state(State::Sleeping(wake_tick(ms))));
+    reschedule();
+}
+
+fn wake_tick(ms: u32) -> u64 {
+    let ticks = (ms as u64 * unsafe { TICK_HZ } as u64 + 999) / 1000;
+    unsafe { TICKS + ticks.max(1) }
+}
+
+/// Slot of `id`, unless the thread was reaped.
+unsafe fn slot_of(id: ThreadId) -> Option<usize> {
+    match THREADS.get(id.slot) {
+        Some(Some(t)) if t.generation == id.generation => Some(id.slot),
+        _ => None,
+    }
+}
+
+/// The thread `id`, unless it was reaped.
+unsafe fn get(id: ThreadId) -> Option<Thread> {
+    slot_of(id).and_then(|slot| THREADS[slot])
+}
+
+/// Wait until thread `id` finishes.
+pub fn join(id: ThreadId) -> Result<()> {
+    if id.slot >= MAX_THREADS || id == current() {
+        return Err(ThreadError::InvalidThread);
+    }
+    set_state(State::Joining(id));
+    reschedule();
+    Ok(())
+}
+
+/// Whether `id` has returned or exited. Also true once it is reaped.
+#[allow(dead_code)]
+pub fn is_finished(id: ThreadId) -> bool {
+    critical_section(|| unsafe { get(id).map_or(true, |t| t.state == State::Done) })
+}
+
+/// Name of the thread whose stack guard contains `addr`.
+pub fn guard_owner(addr: u64) -> Option<&'static str> {
+    unsafe {
+        THREADS.iter().filter_map(|t| t.as_ref()).find_map(|t| {
+            match mmu::stack_guard(t.stack as u64) {
+                Some((start, end)) if addr >= start && addr < end => Some(t.name),
+                _ => None,
+            }
+        })
+    }
+}
+
+pub fn current() -> ThreadId {
+    unsafe {
+        ThreadId {
+            slot: CURRENT,
+            generation: THREADS[CURRENT].map_or(0, |t| t.generation),
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub fn name(id: ThreadId) -> Option<&'static str> {
+    unsafe { get(id).map(|t| t.name) }
+}
+
+/// Ticks since `init`.
+#[allow(dead_code)]
+pub fn ticks() -> u64 {
+    critical_section(|| unsafe { TICKS })
+}
+
+fn set_state(state: State) {
+    critical_section(|| unsafe {
+        if let Some(t) = THREADS[CURRENT].as_mut() {
+            t.state = state;
+        }
+    });
+}
+
+/// Ask the exception vector to switch. Must not be called from IRQ handlers.
+pub fn reschedule() {
+    unsafe { asm!("svc #0" ::: "memory" : "volatile") };
+}
+
+/// Put the running thread on the wait queue `key`. It stops at the next `reschedule`,
+/// unless woken before it.
+///
+/// Call with IRQ masked, after checking the condition waited for, so no wake up is lost.
+pub fn prepare_block(key: usize) {
+    set_state(State::Blocked(key));
+}
+
+/// Wake the highest priority thread waiting on `key`. Callable from IRQ context.
+pub fn wake_one(key: usize) -> bool {
+    critical_section(|| unsafe {
+        let mut best: Option<(Priority, usize)> = None;
+        for slot in 0..MAX_THREADS {
+            if let Some(t) = THREADS[slot] {
+                if t.state == State::Blocked(key) && best.map_or(true, |(p, _)| t.priority < p) {
+                    best = Some((t.priority, slot));
+                }
+            }
+        }
+        match best {
+            Some((_, slot)) => {
+                wake(slot);
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Wake every thread waiting on `key`. Callable from IRQ context.
+pub fn wake_all(key: usize) -> usize {
+    critical_section(|| unsafe {
+        let mut woken = 0;
+        for slot in 0..MAX_THREADS {
+            if THREADS[slot].map_or(false, |t| t.state == State::Blocked(key)) {
+                wake(slot);
+                woken += 1;
+            }
+        }
+        woken
+    })
+}
+
+/// Make the thread in `slot` ready, and request a switch if it should preempt the running thread.
+unsafe fn wake(slot: usize) {
+    if let Some(t) = THREADS[slot].as_mut() {
+        t.state = State::Ready;
+        if THREADS[CURRENT].map_or(false, |c| t.priority < c.priority) {
+            NEED_RESCHED = true;
+        }
+    }
+}
+
+/// Switch at the end of the current IRQ, as if a tick had passed. For the reschedule IPI.
+/// Threads only run on core 0, other cores ignore it.
+pub fn request_reschedule() {
+    if local_intc::core_id() == 0 {
+        critical_section(|| unsafe { NEED_RESCHED = true });
+    }
+}
+
+/// Switch now if a woken thread should preempt the caller. No-op in IRQ context,
+/// where the switch happens on return from the outermost handler.
+pub fn preempt_if_needed() {
+    if exception::in_irq() {
+        return;
+    }
+    if critical_section(|| unsafe { NEED_RESCHED }) {
+        reschedule();
+    }
+}
+
+pub fn priority(id: ThreadId) -> Option<Priority> {
+    unsafe { get(id).map(|t| t.priority) }
+}
+
+/// Lend `prio` to `id` while it blocks a thread of that priority. Only raises.
+pub fn inherit_priority(id: ThreadId, prio: Priority) {
+    critical_section(|| unsafe {
+        if let Some(t) = slot_of(id).and_then(|slot| THREADS[slot].as_mut()) {
+            if prio < t.priority {
+                t.priority = prio;
+            }
+        }
+    });
+}
+
+/// Drop priority lent by `inherit_priority` to the running thread.
+pub fn restore_priority() {
+    critical_section(|| unsafe {
+        if let Some(t) = THREADS[CURRENT].as_mut() {
+            t.priority = t.base_priority;
+        }
+    });
+}
+
+/// Pick the context to return to from an exception. `forced` when the running thread asked
+/// with `svc`, otherwise only switches when a tick has passed.
+///
+/// # Safety
+///
+/// - Call from the IRQ or SVC vector of core 0 at the outermost level, with IRQ masked.
+pub unsafe fn switch(e: *mut ExceptionContext, forced: bool) -> *mut ExceptionContext {
+    if THREADS[MAIN].is_none() || !(forced || NEED_RESCHED) {
+        return e;
+    }
+    NEED_RESCHED = false;
+
+    let prev = CURRENT;
+    if let Some(t) = THREADS[prev].as_mut() {
+        t.context = e;
+    }
+    let next = pick_next(prev);
+    CURRENT = next;
+    match THREADS[next] {
+        Some(t) => {
+            mmu::switch_ttbr0(t.space.as_ref().map_or(mmu::kernel_ttbr0(), |s| s.ttbr0()));
+            t.context
+        }
+        None => e,
+    }
+}
+
+/// `exit` on behalf of the thread which raised exception `e`, e.g. from a system call.
+/// Returns the context to switch to.
+///
+/// # Safety
+///
+/// - Same as `switch`.
+pub unsafe fn exit_from(e: *mut ExceptionContext) -> *mut ExceptionContext {
+    set_state(State::Done);
+    switch(e, true)
+}
+
+/// `sleep` on behalf of the thread which raised exception `e`.
+///
+/// # Safety
+///
+/// - Same as `switch`.
+pub unsafe fn sleep_from(e: *mut ExceptionContext, ms: u32) -> *mut ExceptionContext {
+    set_state(State::Sleeping(wake_tick(ms)));
+    switch(e, true)
+}
+
+/// Block on `key` on behalf of the thread which raised exception `e`.
+///
+/// # Safety
+///
+/// - Same as `switch`.
+pub unsafe fn block_from(e: *mut ExceptionContext, key: usize) -> *mut ExceptionContext {
+    prepare_block(key);
+    switch(e, true)
+}
+
+unsafe fn is_runnable(t: &Thread) -> bool {
+    match t.state {
+        State::Ready => true,
+        State::Sleeping(until) => TICKS >= until,
+        State::Joining(id) => get(id).map_or(true, |j| j.state == State::Done),
+        State::Blocked(_) => false,
+        State::Done => false,
+    }
+}
+
+/// Highest priority runnable thread. Among equals, the first one after `prev`.
+unsafe fn pick_next(prev: usize) -> usize {
+    let mut best: Option<(Priority, usize)> = None;
+    for i in 1..=MAX_THREADS {
+        let slot = (prev + i) % MAX_THREADS;
+        if let Some(t) = THREADS[slot] {
+            if is_runnable(&t) && best.map_or(true, |(p, _)| t.priority < p) {
+                best = Some((t.priority, slot));
+            }
+        }
+    }
+
+    // idle is always runnable.
+    match best {
+        Some((_, slot)) => {
+            if let Some(t) = THREADS[slot].as_mut() {
+                t.state = State::Ready;
+            }
+            slot
+        }
+        None => prev,
+    }
+}
+
+/// Free stacks and address spaces of finished threads. The running one is still on its stack.
+/// Joiners see a reaped thread as finished, see `ThreadId`.
+unsafe fn reap() {
+    for slot in 0..MAX_THREADS {
+        if slot == CURRENT {
+            continue;
+        }
+        if let Some(t) = THREADS[slot] {
+            if t.state == State::Done {
+                free_stack(t.stack, t.stack_size);
+                if !t.space.is_null() {
+                    drop(Box::from_raw(t.space));
+                }
+                THREADS[slot] = None;
+            }
+        }
+    }
+}
@@ -0,0 +1,301 @@
+//! Blocking primitives for threads. Waiters sleep in the scheduler instead of spinning.
+//!
+//! Signalling side (`Semaphore::release`, `Condvar::notify_*`, `Queue::try_send`) is callable
+//! from `InterruptionSource::on_interruption`. Threads are on core 0 only, so masking IRQ is
+//! enough to protect the state.
+
+use crate::sync::critical_section;
+use crate::thread::{self, ThreadId};
+use alloc::collections::VecDeque;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+/// Wait queue of an object is identified by the address of one of its fields.
+fn key<T>(field: &T) -> usize {
+    field as *const T as usize
+}
+
+pub struct Semaphore {
+    count: UnsafeCell<u32>,
+}
+
+unsafe impl Sync for Semaphore {}
+
+#[allow(dead_code)]
+impl Semaphore {
+    pub const fn new(count: u32) -> Semaphore {
+        Semaphore {
+            count: UnsafeCell::new(count),
+        }
+    }
+
+    /// Take one, sleeping until available.
+    pub fn acquire(&self) {
+        loop {
+            let taken = critical_section(|| unsafe {
+                if *self.count.get() > 0 {
+                    *self.count.get() -= 1;
+                    return true;
+                }
+                thread::prepare_block(key(&self.count));
+                false
+            });
+            if taken {
+                return;
+            }
+            thread::reschedule();
+        }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        critical_section(|| unsafe {
+            if *self.count.get() > 0 {
+                *self.count.get() -= 1;
+                true
+            } else {
+                false
+            }
+        })
+    }
+
+    /// Give one back. Callable from IRQ context.
+    pub fn release(&self) {
+        critical_section(|| unsafe {
+            *self.count.get() += 1;
+            thread::wake_one(key(&self.count));
+        });
+        thread::preempt_if_needed();
+    }
+
+    pub fn count(&self) -> u32 {
+        critical_section(|| unsafe { *self.count.get() })
+    }
+}
+
+/// Sleeping lock for threads, with priority inheritance: the owner runs at the priority of
+/// the highest waiter until it unlocks. Not for IRQ handlers; use `sync::Mutex` there.
+///
+/// Inheritance is not transitive, and unlocking drops the owner back to its own priority
+/// even if it still holds another contended mutex.
+pub struct Mutex<T> {
+    owner: UnsafeCell<Option<ThreadId>>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+#[allow(dead_code)]
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Mutex<T> {
+        Mutex {
+            owner: UnsafeCell::new(None),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<T> {
+        let me = thread::current();
+        loop {
+            let taken = critical_section(|| unsafe {
+                match *self.owner.get() {
+                    None => {
+                        *self.owner.get() = Some(me);
+                        true
+                    }
+                    Some(owner) => {
+                        if let Some(prio) = thread::priority(me) {
+                            thread::inherit_priority(owner, prio);
+                        }
+                        thread::prepare_block(key(&self.owner));
+                        false
+                    }
+                }
+            });
+            if taken {
+                return MutexGuard { mutex: self };
+            }
+            thread::reschedule();
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        critical_section(|| unsafe {
+            if (*self.owner.get()).is_none() {
+                *self.owner.get() = Some(thread::current());
+                Some(MutexGuard { mutex: self })
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Release without switching to a woken waiter. Call with IRQ masked.
+    unsafe fn release(&self) {
+        *self.owner.get() = None;
+        thread::restore_priority();
+        thread::wake_one(key(&self.owner));
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        critical_section(|| unsafe { self.mutex.release() });
+        thread::preempt_if_needed();
+    }
+}
+
+pub struct Condvar {
+    /// Only its address is used, as the wait queue key.
+    waiters: u8,
+}
+
+#[allow(dead_code)]
+impl Condvar {
+    pub const fn new() -> Condvar {
+        Condvar { waiters: 0 }
+    }
+
+    /// Unlock, sleep until notified, then lock again. May wake up spuriously:
+    /// check the condition in a loop.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        // unlocking and blocking at once, or a notify may slip in between.
+        critical_section(|| {
+            thread::prepare_block(key(&self.waiters));
+            unsafe { mutex.release() };
+        });
+        core::mem::forget(guard);
+
+        thread::reschedule();
+        mutex.lock()
+    }
+
+    /// Callable from IRQ context.
+    pub fn notify_one(&self) {
+        thread::wake_one(key(&self.waiters));
+        thread::preempt_if_needed();
+    }
+
+    /// Callable from IRQ context.
+    pub fn notify_all(&self) {
+        thread::wake_all(key(&self.waiters));
+        thread::preempt_if_needed();
+    }
+}
+
+/// Bounded FIFO. `send` and `recv` sleep on full and empty, `try_send` and `try_recv` don't
+/// and are callable from IRQ context.
+pub struct Queue<T> {
+    items: UnsafeCell<VecDeque<T>>,
+    capacity: usize,
+}
+
+unsafe impl<T: Send> Sync for Queue<T> {}
+
+#[allow(dead_code)]
+impl<T> Queue<T> {
+    /// Allocates room for `capacity` items up front, so IRQ handlers never allocate.
+    pub fn new(capacity: usize) -> Queue<T> {
+        Queue {
+            items: UnsafeCell::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    pub fn try_send(&self, item: T) -> Result<(), T> {
+        let r = critical_section(|| unsafe {
+            let items = &mut *self.items.get();
+            if items.len() >= self.capacity {
+                return Err(item);
+            }
+            items.push_back(item);
+            thread::wake_one(key(&self.items));
+            Ok(())
+        });
+        thread::preempt_if_needed();
+        r
+    }
+
+    pub fn send(&self, item: T) {
+        let mut item = item;
+        loop {
+            match self.try_send_or_block(item) {
+                Ok(()) => {
+                    thread::preempt_if_needed();
+                    return;
+                }
+                Err(back) => item = back,
+            }
+            thread::reschedule();
+        }
+    }
+
+    pub fn try_recv(&self) -> Option<T> {
+        let item = critical_section(|| unsafe {
+            let item = (*self.items.get()).pop_front();
+            if item.is_some() {
+                thread::wake_one(key(&self.capacity));
+            }
+            item
+        });
+        thread::preempt_if_needed();
+        item
+    }
+
+    pub fn recv(&self) -> T {
+        loop {
+            let item = critical_section(|| unsafe {
+                let item = (*self.items.get()).pop_front();
+                if item.is_some() {
+                    thread::wake_one(key(&self.capacity));
+                } else {
+                    thread::prepare_block(key(&self.items));
+                }
+                item
+            });
+            match item {
+                Some(item) => {
+                    thread::preempt_if_needed();
+                    return item;
+                }
+                None => thread::reschedule(),
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        critical_section(|| unsafe { (*self.items.get()).len() })
+    }
+
+    /// Push, or prepare to sleep until there is room.
+    fn try_send_or_block(&self, item: T) -> Result<(), T> {
+        critical_section(|| unsafe {
+            let items = &mut *self.items.get();
+            if items.len() >= self.capacity {
+                thread::prepare_block(key(&self.capacity));
+                return Err(item);
+            }
+            items.push_back(item);
+            thread::wake_one(key(&self.items));
+            Ok(())
+        })
+    }
+}
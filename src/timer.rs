@@ -1,13 +1,14 @@
-use crate::optional_cell::OptionalCell;
+use crate::event::EventFlags;
 use register::{
     mmio::{ReadOnly, ReadWrite},
     register_bitfields,
 };
 
-const TIMER_BASE: u32 = super::MMIO_BASE + 0x3000;
+const TIMER_BASE: u64 = super::MMIO_BASE + 0x3000;
 
 pub struct TIMER {
-    occurred: [OptionalCell<bool>; 4],
+    /// bit n for channel n.
+    events: EventFlags,
 }
 
 #[allow(non_snake_case)]
@@ -58,11 +59,11 @@ impl core::ops::Deref for TIMER {
 }
 
 impl crate::exception::InterruptionSource for TIMER {
-    fn on_interruption(&self, _id: u32) {
+    fn on_interruption(&self, _source: crate::interrupt::IrqSource) {
         for ch in 0..=3 {
             if self.is_match(ch) {
                 self.clear(ch);
-                self.occurred[ch as usize].set(true);
+                self.events.set(1 << ch);
             }
         }
     }
@@ -72,7 +73,7 @@ impl crate::exception::InterruptionSource for TIMER {
 impl TIMER {
     pub fn new() -> TIMER {
         TIMER {
-            occurred: arr_macro::arr![OptionalCell::empty();4],
+            events: EventFlags::new(),
         }
     }
 
@@ -89,6 +90,11 @@ impl TIMER {
         self.CLO.read(CLO::TIME)
     }
 
+    /// Read the 1MHz counter without an instance, e.g. from IRQ context.
+    pub fn now() -> u32 {
+        unsafe { (*Self::ptr()).CLO.read(CLO::TIME) }
+    }
+
     pub fn set(&self, ch: u32, t: u32) {
         let r = match ch {
             0 => &self.C0,
@@ -124,12 +130,12 @@ impl TIMER {
 
     pub fn occurred(&self, ch: usize) -> bool {
         match ch {
-            // take() returns a value and leave None.
-            0 | 1 | 2 | 3 => match self.occurred[ch].take() {
-                Some(v) => v,
-                None => false,
-            },
+            0 | 1 | 2 | 3 => self.events.take(1 << ch) != 0,
             _ => false,
         }
     }
+
+    pub fn events(&self) -> &EventFlags {
+        &self.events
+    }
 }
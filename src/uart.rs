@@ -25,6 +25,7 @@
 use super::MMIO_BASE;
 use crate::gpio;
 use crate::mbox;
+use crate::sync::Mutex;
 use core::{
     ops,
     sync::atomic::{compiler_fence, Ordering},
@@ -118,7 +119,7 @@ register_bitfields! {
     ]
 }
 
-const UART_BASE: u32 = MMIO_BASE + 0x20_1000;
+const UART_BASE: u64 = MMIO_BASE + 0x20_1000;
 
 #[allow(non_snake_case)]
 #[repr(C)]
@@ -142,6 +143,9 @@ pub type Result<T> = ::core::result::Result<T, UartError>;
 
 pub struct Uart;
 
+/// Every core prints, also from IRQ handlers. Characters of one call are not mixed with others.
+static LOCK: Mutex<()> = Mutex::new(());
+
 impl ops::Deref for Uart {
     type Target = RegisterBlock;
 
@@ -263,6 +267,7 @@ impl Uart {
 
     /// Display a string
     pub fn puts(&self, string: &str) {
+        let _lock = LOCK.lock();
         for c in string.chars() {
             // convert newline to carrige return + newline
             if c == '\n' {
@@ -275,6 +280,7 @@ impl Uart {
 
     /// Display a binary value in hexadecimal
     pub fn hex(&self, d: u32) {
+        let _lock = LOCK.lock();
         let mut n;
 
         for i in 0..8 {
@@ -0,0 +1,124 @@
+use register::{mmio::ReadWrite, register_bitfields};
+
+const PM_BASE: u64 = super::MMIO_BASE + 0x10_001C;
+
+/// Every write to PM registers must carry this in bit 31-24.
+const PASSWORD: u32 = 0x5A;
+
+/// Watchdog counter runs at 65536 ticks per sec.
+const TICKS_PER_SEC: u64 = 0x1_0000;
+const TIMEOUT_MAX: u32 = 0xF_FFFF; // 20 bits, almost 16 sec.
+
+pub struct Watchdog {
+    timeout: core::cell::Cell<u32>,
+}
+
+pub enum WatchdogError {
+    InvalidTimeout,
+}
+pub type Result<T> = ::core::result::Result<T, WatchdogError>;
+
+#[allow(non_snake_case)]
+#[repr(C)]
+pub struct RegisterBlock {
+    RSTC: ReadWrite<u32, RSTC::Register>, // 0x1c
+    RSTS: ReadWrite<u32, RSTS::Register>, // 0x20
+    WDOG: ReadWrite<u32, WDOG::Register>, // 0x24
+}
+
+register_bitfields! {
+    u32,
+    RSTC[
+        PASSWD OFFSET(24) NUMBITS(8)[],
+        WRCFG OFFSET(4) NUMBITS(2)[
+            Clear = 0,
+            FullReset = 2
+        ]
+    ],
+    RSTS[
+        PASSWD OFFSET(24) NUMBITS(8)[],
+        /// Set when the last reset was caused by the watchdog.
+        HADWRH OFFSET(6) NUMBITS(1)[]
+    ],
+    WDOG[
+        PASSWD OFFSET(24) NUMBITS(8)[],
+        /// Ticks left until reset.
+        TIME OFFSET(0) NUMBITS(20)[]
+    ]
+}
+
+impl core::ops::Deref for Watchdog {
+    type Target = RegisterBlock;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*Self::ptr() }
+    }
+}
+
+impl Watchdog {
+    pub fn new() -> Watchdog {
+        Watchdog {
+            timeout: core::cell::Cell::new(0),
+        }
+    }
+
+    fn ptr() -> *const RegisterBlock {
+        PM_BASE as *const _
+    }
+
+    fn ms_to_ticks(ms: u32) -> u32 {
+        (ms as u64 * TICKS_PER_SEC / 1000) as u32
+    }
+
+    /// Arm the watchdog. Board is reset unless `feed` is called within `timeout_ms`.
+    pub fn start(&self, timeout_ms: u32) -> Result<()> {
+        let ticks = Self::ms_to_ticks(timeout_ms);
+        if ticks == 0 || ticks > TIMEOUT_MAX {
+            return Err(WatchdogError::InvalidTimeout);
+        }
+        self.timeout.set(ticks);
+        self.set_timer(ticks);
+        Ok(())
+    }
+
+    /// Restart counting from the timeout given to `start`.
+    pub fn feed(&self) {
+        let ticks = self.timeout.get();
+        if ticks != 0 {
+            self.WDOG
+                .write(WDOG::PASSWD.val(PASSWORD) + WDOG::TIME.val(ticks));
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.timeout.get() != 0
+    }
+
+    /// Remaining time in ms until reset.
+    pub fn time_left(&self) -> u32 {
+        (self.WDOG.read(WDOG::TIME) as u64 * 1000 / TICKS_PER_SEC) as u32
+    }
+
+    /// Whether the last reset was caused by the watchdog.
+    pub fn reset_by_watchdog(&self) -> bool {
+        self.RSTS.is_set(RSTS::HADWRH)
+    }
+
+    /// Reset the board as soon as possible.
+    pub fn reboot(&self) -> ! {
+        self.set_timer(10);
+        loop {
+            unsafe {
+                asm!("wfe" :::: "volatile");
+            }
+        }
+    }
+
+    fn set_timer(&self, ticks: u32) {
+        self.WDOG
+            .write(WDOG::PASSWD.val(PASSWORD) + WDOG::TIME.val(ticks));
+        // keep other bits in RSTC as they are.
+        self.RSTC
+            .modify(RSTC::PASSWD.val(PASSWORD) + RSTC::WRCFG::FullReset);
+    }
+}